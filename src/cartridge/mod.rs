@@ -0,0 +1,517 @@
+use std::fmt::{Display, Formatter};
+use std::fs;
+
+pub mod save;
+
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PRG_BANK_SIZE: usize = 16384;
+const CHR_BANK_SIZE: usize = 8192;
+
+const MAGIC: [u8; 4] = [b'N', b'E', b'S', 0x1A];
+
+const FLAG6_VERTICAL: u8    = 0b00000001;
+const FLAG6_BATTERY: u8     = 0b00000010;
+const FLAG6_TRAINER: u8     = 0b00000100;
+const FLAG6_FOURSCREEN: u8  = 0b00001000;
+const FLAG9_PAL: u8         = 0b00000001;
+
+// Bits 2-3 of byte 7 are %10 for NES 2.0 headers.
+const FLAG7_FORMAT_MASK: u8 = 0b00001100;
+const FLAG7_FORMAT_NES2: u8 = 0b00001000;
+const FLAG7_CONSOLE_MASK: u8 = 0b00000011;
+
+// iNES 1.0 files do not say how much work RAM a board has, most have 8 KiB.
+const DEFAULT_PRG_RAM_SIZE: usize = 8192;
+const DEFAULT_CHR_RAM_SIZE: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mirroring
+{
+    Horizontal,
+    Vertical,
+    FourScreen,
+    // All four nametables show the first (A) or second (B) 1 KiB of CIRAM,
+    // only mappers can select these.
+    SingleScreenA,
+    SingleScreenB
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region
+{
+    Ntsc,
+    Pal,
+    Multi,
+    Dendy
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeaderFormat
+{
+    INes,
+    Nes2
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsoleType
+{
+    Nes,
+    VsSystem,
+    PlayChoice10,
+    // Extended console type from byte 13, e.g. Famiclone with decimal mode.
+    Extended(u8)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CartridgeError
+{
+    Io(String),
+    BadMagic([u8; 4]),
+    // A NES 2.0 size field that does not fit into memory.
+    InvalidSize { section: &'static str },
+    UnsupportedMapper(u16),
+    // The board needs more of section than the file has, e.g. a fixed
+    // 16 KiB PRG bank.
+    TooSmall { section: &'static str, minimum: usize, found: usize },
+    // The file ended before section was complete.
+    Truncated { section: &'static str, expected: usize, found: usize }
+}
+
+impl Display for CartridgeError
+{
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result
+    {
+        match self
+        {
+            CartridgeError::Io(msg) => write!(f, "Cannot read ROM file: {}", msg),
+            CartridgeError::BadMagic(magic) => write!(f, "Not an iNES file, header starts with {:02X?} instead of \"NES\\x1A\"", magic),
+            CartridgeError::InvalidSize { section } => write!(f, "Invalid {} size in NES 2.0 header", section),
+            CartridgeError::UnsupportedMapper(mapper) => write!(f, "Mapper {} is not supported", mapper),
+            CartridgeError::TooSmall { section, minimum, found } =>
+                write!(f, "{} is {} bytes, the board needs at least {}", section, found, minimum),
+            CartridgeError::Truncated { section, expected, found } =>
+                write!(f, "ROM file is truncated: {} needs {} bytes, only {} left", section, expected, found)
+        }
+    }
+}
+
+// Everything we know about a game after parsing its ROM file. Fields that
+// only NES 2.0 headers carry are filled with the usual iNES 1.0 defaults.
+pub struct Cartridge
+{
+    pub format: HeaderFormat,
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    pub trainer: Option<Vec<u8>>,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub region: Region,
+    pub console: ConsoleType,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize
+}
+
+impl Cartridge
+{
+    pub fn load(romfile: &str) -> Result<Self, CartridgeError>
+    {
+        let data = fs::read(romfile).map_err(|e| CartridgeError::Io(format!("{}: {}", romfile, e)))?;
+        Cartridge::from_bytes(&data)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, CartridgeError>
+    {
+        let mut rd = Reader { data, pos: 0 };
+        let header = rd.take("header", HEADER_SIZE)?;
+
+        if header[0..4] != MAGIC
+        {
+            return Err(CartridgeError::BadMagic([header[0], header[1], header[2], header[3]]));
+        }
+
+        let flags6 = header[6];
+        let format = if header[7] & FLAG7_FORMAT_MASK == FLAG7_FORMAT_NES2 { HeaderFormat::Nes2 } else { HeaderFormat::INes };
+
+        let trainer = if flags6 & FLAG6_TRAINER != 0
+        {
+            Some(rd.take("trainer", TRAINER_SIZE)?.to_vec())
+        }
+        else
+        {
+            None
+        };
+
+        let mirroring = if flags6 & FLAG6_FOURSCREEN != 0
+        {
+            Mirroring::FourScreen
+        }
+        else if flags6 & FLAG6_VERTICAL != 0
+        {
+            Mirroring::Vertical
+        }
+        else
+        {
+            Mirroring::Horizontal
+        };
+
+        let battery = flags6 & FLAG6_BATTERY != 0;
+
+        match format
+        {
+            HeaderFormat::INes => {
+                // Old dumping tools wrote their name ("DiskDude!") into bytes
+                // 7-15, in that case byte 7 does not hold the upper mapper
+                // nibble.
+                let flags7 = if header[12..16].iter().all(|&x| x == 0) { header[7] } else { 0 };
+                let prg_size = header[4] as usize * PRG_BANK_SIZE;
+                let chr_size = header[5] as usize * CHR_BANK_SIZE;
+
+                Ok(Cartridge {
+                    format,
+                    prg_rom: rd.take("PRG ROM", prg_size)?.to_vec(),
+                    chr_rom: rd.take("CHR ROM", chr_size)?.to_vec(),
+                    trainer,
+                    mapper: ((flags7 & 0xF0) | (flags6 >> 4)) as u16,
+                    submapper: 0,
+                    mirroring,
+                    battery,
+                    region: if header[9] & FLAG9_PAL != 0 { Region::Pal } else { Region::Ntsc },
+                    console: ConsoleType::Nes,
+                    prg_ram_size: if battery { 0 } else { DEFAULT_PRG_RAM_SIZE },
+                    prg_nvram_size: if battery { DEFAULT_PRG_RAM_SIZE } else { 0 },
+                    chr_ram_size: if chr_size == 0 { DEFAULT_CHR_RAM_SIZE } else { 0 },
+                    chr_nvram_size: 0
+                })
+            },
+            HeaderFormat::Nes2 => {
+                let prg_size = Cartridge::nes2_rom_size("PRG ROM", header[4], header[9] & 0x0F, PRG_BANK_SIZE)?;
+                let chr_size = Cartridge::nes2_rom_size("CHR ROM", header[5], header[9] >> 4, CHR_BANK_SIZE)?;
+                let console = match header[7] & FLAG7_CONSOLE_MASK
+                {
+                    0 => ConsoleType::Nes,
+                    1 => ConsoleType::VsSystem,
+                    2 => ConsoleType::PlayChoice10,
+                    _ => ConsoleType::Extended(header[13] & 0x0F)
+                };
+                let region = match header[12] & 0x03
+                {
+                    0 => Region::Ntsc,
+                    1 => Region::Pal,
+                    2 => Region::Multi,
+                    _ => Region::Dendy
+                };
+
+                Ok(Cartridge {
+                    format,
+                    prg_rom: rd.take("PRG ROM", prg_size)?.to_vec(),
+                    chr_rom: rd.take("CHR ROM", chr_size)?.to_vec(),
+                    trainer,
+                    mapper: ((header[8] as u16 & 0x0F) << 8) | (header[7] & 0xF0) as u16 | (flags6 >> 4) as u16,
+                    submapper: header[8] >> 4,
+                    mirroring,
+                    battery,
+                    region,
+                    console,
+                    prg_ram_size: Cartridge::nes2_ram_size(header[10] & 0x0F),
+                    prg_nvram_size: Cartridge::nes2_ram_size(header[10] >> 4),
+                    chr_ram_size: Cartridge::nes2_ram_size(header[11] & 0x0F),
+                    chr_nvram_size: Cartridge::nes2_ram_size(header[11] >> 4)
+                })
+            }
+        }
+    }
+
+    // ROM sizes are either a 12 bit bank count or, if the upper nibble is
+    // $F, 2^E * (MM * 2 + 1) bytes with the low byte laid out as EEEEEEMM.
+    fn nes2_rom_size(section: &'static str, lsb: u8, msb: u8, bank_size: usize) -> Result<usize, CartridgeError>
+    {
+        if msb != 0x0F
+        {
+            return Ok((((msb as usize) << 8) | lsb as usize) * bank_size);
+        }
+
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0x03) as usize * 2 + 1;
+        1usize.checked_shl(exponent)
+              .and_then(|x| x.checked_mul(multiplier))
+              .ok_or(CartridgeError::InvalidSize { section })
+    }
+
+    // RAM sizes are given as shift counts: 64 << n bytes, 0 means none.
+    fn nes2_ram_size(shift: u8) -> usize
+    {
+        if shift == 0 { 0 } else { 64 << shift }
+    }
+}
+
+struct Reader<'a>
+{
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Reader<'a>
+{
+    fn take(&mut self, section: &'static str, len: usize) -> Result<&'a [u8], CartridgeError>
+    {
+        let left = self.data.len() - self.pos;
+        if left < len
+        {
+            return Err(CartridgeError::Truncated { section, expected: len, found: left });
+        }
+        let res = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod cartridgetests
+{
+    use crate::cartridge::*;
+
+    fn ines(prg_banks: u8, chr_banks: u8, flags6: u8, flags7: u8) -> Vec<u8>
+    {
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, prg_banks, chr_banks, flags6, flags7, 0, 0, 0, 0, 0, 0, 0, 0];
+        if flags6 & FLAG6_TRAINER != 0
+        {
+            rom.extend(vec![0x77; TRAINER_SIZE]);
+        }
+        rom.extend(vec![0xAA; prg_banks as usize * PRG_BANK_SIZE]);
+        rom.extend(vec![0x55; chr_banks as usize * CHR_BANK_SIZE]);
+        rom
+    }
+
+    #[test]
+    fn parses_prg_and_chr()
+    {
+        let cart = Cartridge::from_bytes(&ines(2, 1, 0x00, 0x00)).unwrap();
+
+        assert_eq!(2 * PRG_BANK_SIZE, cart.prg_rom.len());
+        assert_eq!(CHR_BANK_SIZE, cart.chr_rom.len());
+        assert!(cart.prg_rom.iter().all(|&x| x == 0xAA));
+        assert!(cart.chr_rom.iter().all(|&x| x == 0x55));
+        assert_eq!(None, cart.trainer);
+        assert_eq!(Region::Ntsc, cart.region);
+    }
+
+    #[test]
+    fn parses_mapper_number_from_both_nibbles()
+    {
+        let cart = Cartridge::from_bytes(&ines(1, 1, 0x40, 0x40)).unwrap();
+        assert_eq!(0x44, cart.mapper);
+    }
+
+    #[test]
+    fn ignores_flags7_with_garbage_in_header_tail()
+    {
+        let mut rom = ines(1, 1, 0x10, 0x40);
+        rom[12..16].copy_from_slice(b"Dude");
+
+        let cart = Cartridge::from_bytes(&rom).unwrap();
+
+        assert_eq!(0x01, cart.mapper);
+    }
+
+    #[test]
+    fn parses_mirroring_and_battery()
+    {
+        assert_eq!(Mirroring::Horizontal, Cartridge::from_bytes(&ines(1, 1, 0x00, 0)).unwrap().mirroring);
+        assert_eq!(Mirroring::Vertical, Cartridge::from_bytes(&ines(1, 1, FLAG6_VERTICAL, 0)).unwrap().mirroring);
+        assert_eq!(Mirroring::FourScreen, Cartridge::from_bytes(&ines(1, 1, FLAG6_FOURSCREEN | FLAG6_VERTICAL, 0)).unwrap().mirroring);
+
+        assert!(Cartridge::from_bytes(&ines(1, 1, FLAG6_BATTERY, 0)).unwrap().battery);
+        assert!(!Cartridge::from_bytes(&ines(1, 1, 0x00, 0)).unwrap().battery);
+    }
+
+    #[test]
+    fn reads_trainer_before_prg()
+    {
+        let cart = Cartridge::from_bytes(&ines(1, 0, FLAG6_TRAINER, 0)).unwrap();
+
+        assert_eq!(Some(vec![0x77; TRAINER_SIZE]), cart.trainer);
+        assert!(cart.prg_rom.iter().all(|&x| x == 0xAA));
+    }
+
+    #[test]
+    fn parses_pal_flag()
+    {
+        let mut rom = ines(1, 1, 0, 0);
+        rom[9] = FLAG9_PAL;
+        assert_eq!(Region::Pal, Cartridge::from_bytes(&rom).unwrap().region);
+    }
+
+    #[test]
+    fn rejects_bad_magic()
+    {
+        let mut rom = ines(1, 1, 0, 0);
+        rom[3] = 0x00;
+
+        assert_eq!(Err(CartridgeError::BadMagic([b'N', b'E', b'S', 0x00])), Cartridge::from_bytes(&rom).map(|_| ()));
+    }
+
+    #[test]
+    fn rejects_short_header()
+    {
+        assert_eq!(Err(CartridgeError::Truncated { section: "header", expected: HEADER_SIZE, found: 4 }),
+                   Cartridge::from_bytes(b"NES\x1A").map(|_| ()));
+    }
+
+    #[test]
+    fn rejects_truncated_prg()
+    {
+        let mut rom = ines(2, 0, 0, 0);
+        rom.truncate(HEADER_SIZE + PRG_BANK_SIZE);
+
+        assert_eq!(Err(CartridgeError::Truncated { section: "PRG ROM", expected: 2 * PRG_BANK_SIZE, found: PRG_BANK_SIZE }),
+                   Cartridge::from_bytes(&rom).map(|_| ()));
+    }
+
+    #[test]
+    fn rejects_truncated_chr()
+    {
+        let mut rom = ines(1, 1, 0, 0);
+        rom.pop();
+
+        assert_eq!(Err(CartridgeError::Truncated { section: "CHR ROM", expected: CHR_BANK_SIZE, found: CHR_BANK_SIZE - 1 }),
+                   Cartridge::from_bytes(&rom).map(|_| ()));
+    }
+
+    fn nes2(prg_banks: u8, chr_banks: u8, flags6: u8, flags7: u8, tail: [u8; 8]) -> Vec<u8>
+    {
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, prg_banks, chr_banks, flags6, flags7 | FLAG7_FORMAT_NES2];
+        rom.extend_from_slice(&tail);
+        rom
+    }
+
+    #[test]
+    fn ines_defaults_for_nes2_fields()
+    {
+        let cart = Cartridge::from_bytes(&ines(1, 0, 0x00, 0x00)).unwrap();
+
+        assert_eq!(HeaderFormat::INes, cart.format);
+        assert_eq!(0, cart.submapper);
+        assert_eq!(ConsoleType::Nes, cart.console);
+        assert_eq!(8192, cart.prg_ram_size);
+        assert_eq!(0, cart.prg_nvram_size);
+        assert_eq!(8192, cart.chr_ram_size);
+
+        let cart = Cartridge::from_bytes(&ines(1, 1, FLAG6_BATTERY, 0x00)).unwrap();
+        assert_eq!(0, cart.prg_ram_size);
+        assert_eq!(8192, cart.prg_nvram_size);
+        assert_eq!(0, cart.chr_ram_size);
+    }
+
+    #[test]
+    fn falls_back_to_ines_without_format_bits()
+    {
+        // %11 in bits 2-3 is not NES 2.0, so byte 8 must not be used.
+        let mut rom = ines(1, 1, 0x10, 0x0C);
+        rom[8] = 0x21;
+
+        let cart = Cartridge::from_bytes(&rom).unwrap();
+
+        assert_eq!(HeaderFormat::INes, cart.format);
+        assert_eq!(0x01, cart.mapper);
+        assert_eq!(0, cart.submapper);
+    }
+
+    #[test]
+    fn parses_nes2_mapper_and_submapper()
+    {
+        let mut rom = nes2(1, 1, 0x50, 0x40, [0x31, 0, 0, 0, 0, 0, 0, 0]);
+        rom.extend(vec![0x00; PRG_BANK_SIZE + CHR_BANK_SIZE]);
+
+        let cart = Cartridge::from_bytes(&rom).unwrap();
+
+        assert_eq!(HeaderFormat::Nes2, cart.format);
+        assert_eq!(0x145, cart.mapper);
+        assert_eq!(3, cart.submapper);
+    }
+
+    #[test]
+    fn parses_nes2_rom_size_msb()
+    {
+        // 0x102 PRG banks, 0x201 CHR banks
+        let mut rom = nes2(0x02, 0x01, 0, 0, [0, 0x21, 0, 0, 0, 0, 0, 0]);
+        rom.extend(vec![0x00; 0x102 * PRG_BANK_SIZE + 0x201 * CHR_BANK_SIZE]);
+
+        let cart = Cartridge::from_bytes(&rom).unwrap();
+
+        assert_eq!(0x102 * PRG_BANK_SIZE, cart.prg_rom.len());
+        assert_eq!(0x201 * CHR_BANK_SIZE, cart.chr_rom.len());
+    }
+
+    #[test]
+    fn parses_nes2_exponent_rom_size()
+    {
+        // PRG: 2^10 * 3 bytes, CHR: 2^9 * 1 bytes
+        let mut rom = nes2((10 << 2) | 1, 9 << 2, 0, 0, [0, 0xFF, 0, 0, 0, 0, 0, 0]);
+        rom.extend(vec![0x00; 3072 + 512]);
+
+        let cart = Cartridge::from_bytes(&rom).unwrap();
+
+        assert_eq!(3072, cart.prg_rom.len());
+        assert_eq!(512, cart.chr_rom.len());
+    }
+
+    #[test]
+    fn rejects_nes2_exponent_size_that_does_not_fit()
+    {
+        let rom = nes2(63 << 2 | 3, 0, 0, 0, [0, 0x0F, 0, 0, 0, 0, 0, 0]);
+
+        assert_eq!(Err(CartridgeError::InvalidSize { section: "PRG ROM" }), Cartridge::from_bytes(&rom).map(|_| ()));
+    }
+
+    #[test]
+    fn parses_nes2_ram_sizes()
+    {
+        // PRG RAM 64 << 7 = 8K, PRG NVRAM 64 << 6 = 4K, CHR RAM 64 << 7 = 8K, no CHR NVRAM
+        let mut rom = nes2(1, 0, FLAG6_BATTERY, 0, [0, 0, 0x67, 0x07, 0, 0, 0, 0]);
+        rom.extend(vec![0x00; PRG_BANK_SIZE]);
+
+        let cart = Cartridge::from_bytes(&rom).unwrap();
+
+        assert_eq!(8192, cart.prg_ram_size);
+        assert_eq!(4096, cart.prg_nvram_size);
+        assert_eq!(8192, cart.chr_ram_size);
+        assert_eq!(0, cart.chr_nvram_size);
+    }
+
+    #[test]
+    fn parses_nes2_timing_and_console_type()
+    {
+        let timing = |value: u8| {
+            let mut rom = nes2(1, 0, 0, 0, [0, 0, 0, 0, value, 0, 0, 0]);
+            rom.extend(vec![0x00; PRG_BANK_SIZE]);
+            Cartridge::from_bytes(&rom).unwrap().region
+        };
+        assert_eq!(Region::Ntsc, timing(0));
+        assert_eq!(Region::Pal, timing(1));
+        assert_eq!(Region::Multi, timing(2));
+        assert_eq!(Region::Dendy, timing(3));
+
+        let mut rom = nes2(1, 0, 0, 0x01, [0; 8]);
+        rom.extend(vec![0x00; PRG_BANK_SIZE]);
+        assert_eq!(ConsoleType::VsSystem, Cartridge::from_bytes(&rom).unwrap().console);
+
+        let mut rom = nes2(1, 0, 0, 0x03, [0, 0, 0, 0, 0, 0x03, 0, 0]);
+        rom.extend(vec![0x00; PRG_BANK_SIZE]);
+        assert_eq!(ConsoleType::Extended(3), Cartridge::from_bytes(&rom).unwrap().console);
+    }
+
+    #[test]
+    fn missing_file_is_reported()
+    {
+        match Cartridge::load("./does/not/exist.nes")
+        {
+            Err(CartridgeError::Io(msg)) => assert!(msg.contains("exist.nes")),
+            _ => panic!("expected an io error")
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use crate::cartridge::CartridgeError;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// Battery backed PRG RAM lives in <rom>.sav next to the ROM file. The file
+// is only rewritten when the RAM changed, and always through a temporary
+// file that is renamed over the old save, so a crash in the middle of a
+// write leaves the previous save intact.
+pub struct SaveFile
+{
+    path: PathBuf,
+    // RAM contents as last loaded or written.
+    saved: Vec<u8>
+}
+
+impl SaveFile
+{
+    pub fn for_rom(romfile: &str) -> Self
+    {
+        SaveFile { path: Path::new(romfile).with_extension("sav"), saved: Vec::new() }
+    }
+
+    pub fn path(&self) -> &Path
+    {
+        &self.path
+    }
+
+    // Copies the save into ram. Without a save file ram keeps its power on
+    // contents, a file of the wrong size is used as far as it goes.
+    pub fn load(&mut self, ram: &mut [u8]) -> Result<bool, CartridgeError>
+    {
+        let found = match fs::read(&self.path)
+        {
+            Ok(data) => {
+                let len = data.len().min(ram.len());
+                ram[..len].copy_from_slice(&data[..len]);
+                true
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => return Err(self.error(e))
+        };
+        self.saved = ram.to_vec();
+        Ok(found)
+    }
+
+    // Writes ram if it differs from what is on disk. Returns whether it did.
+    pub fn flush(&mut self, ram: &[u8]) -> Result<bool, CartridgeError>
+    {
+        if ram.is_empty() || ram == self.saved.as_slice()
+        {
+            return Ok(false);
+        }
+
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+
+        let mut file = fs::File::create(&temp).map_err(|e| self.error(e))?;
+        file.write_all(ram).and_then(|_| file.sync_all()).map_err(|e| self.error(e))?;
+        fs::rename(&temp, &self.path).map_err(|e| self.error(e))?;
+
+        self.saved = ram.to_vec();
+        Ok(true)
+    }
+
+    fn error(&self, e: std::io::Error) -> CartridgeError
+    {
+        CartridgeError::Io(format!("{}: {}", self.path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod savetests
+{
+    use crate::cartridge::save::*;
+
+    fn romfile(name: &str) -> String
+    {
+        let dir = std::env::temp_dir().join(format!("novanes-save-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("game.nes").to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn save_file_is_next_to_rom()
+    {
+        let save = SaveFile::for_rom("roms/zelda.nes");
+
+        assert_eq!(Path::new("roms/zelda.sav"), save.path());
+    }
+
+    #[test]
+    fn missing_save_keeps_ram()
+    {
+        let mut save = SaveFile::for_rom(&romfile("missing"));
+        let mut ram = [0x55; 16];
+
+        assert!(!save.load(&mut ram).unwrap());
+        assert_eq!([0x55; 16], ram);
+        assert!(!save.flush(&ram).unwrap());
+    }
+
+    #[test]
+    fn flush_writes_changes_and_load_restores_them()
+    {
+        let rom = romfile("roundtrip");
+        let mut save = SaveFile::for_rom(&rom);
+        let mut ram = [0u8; 16];
+        save.load(&mut ram).unwrap();
+
+        ram[3] = 0x42;
+        assert!(save.flush(&ram).unwrap());
+        assert!(!save.flush(&ram).unwrap());
+        assert!(!Path::new(&format!("{}.tmp", save.path().display())).exists());
+
+        let mut restored = [0u8; 16];
+        assert!(SaveFile::for_rom(&rom).load(&mut restored).unwrap());
+        assert_eq!(ram, restored);
+
+        fs::remove_dir_all(Path::new(&rom).parent().unwrap()).unwrap();
+    }
+}
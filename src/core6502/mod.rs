@@ -22,9 +22,9 @@ pub const NMI_VEC: usize = 0xFFFA;
 pub const RES_VEC: usize = 0xFFFC;
 pub const IRQ_VEC: usize = 0xFFFE;
 
-const NMI_MSK: u8 = 0b001;
-const IRQ_MSK: u8 = 0b010;
-const RES_MSK: u8 = 0b100;
+const NMI_MSK: u8 = NMI_LINE;
+const IRQ_MSK: u8 = IRQ_LINE;
+const RES_MSK: u8 = RES_LINE;
 
 pub struct Rico
 {
@@ -42,7 +42,11 @@ pub struct Rico
     current_opcode_nmonic: String,
     current_opcode: u8,
     logger: Arc<Mutex<log::logger>>,
-    pending_irq: u8
+    pending_irq: u8,
+    total_cycles: u64,
+    // Cycles the last instruction (and a DMA stall after it) ran past the
+    // end of the previous execute call, they count against the next one.
+    cycle_debt: u32
 }
 
 impl Rico
@@ -55,14 +59,15 @@ impl Rico
 
     pub fn new (mut mem: Box<dyn Memory>, log: Arc<Mutex<log::logger>> ) -> Self
     {
+        // Nothing might be mapped here (yet), so failed writes are fine.
         for i in 0x4000..0x400F
         {
-            mem.write_byte(i, 0x00);
+            let _ = mem.write_byte(i, 0x00);
         }
 
         for i in 0x4010..0x4013
         {
-           mem.write_byte(i, 0x00); 
+           let _ = mem.write_byte(i, 0x00); 
         }
 
         Rico
@@ -80,7 +85,9 @@ impl Rico
             current_opcode: 0x00,
             current_opcode_nmonic: "<none>".to_string(),
             logger: log,
-            pending_irq: 0
+            pending_irq: 0,
+            total_cycles: 0,
+            cycle_debt: 0
         }
     }
 
@@ -91,27 +98,29 @@ impl Rico
 
     pub fn execute(&mut self, num_cycles: u32)
     {
-        let mut cycle_count = 0;
+        let debt = self.cycle_debt;
+        let mut cycle_count = debt;
         while cycle_count < num_cycles
         {
             // read opcode
-            let opcode = self.mem.read_byte(self.pc as usize);
+            let opcode = self.mem.read_opcode(self.pc as usize);
             match opcode
             {
                 Ok(x) => {
                     
                     let dummypc = self.pc;
 
-                    let break_adr =  0x90e6;
-                    if self.pc == break_adr
-                    {
-                        self.log("Breakpoint hit.".to_string());
-                    }
-
                     self.current_opcode = x;
                     
                     // dispatch opcode
-                    let cylces_taken = self.dispatch_opcode(x);
+                    let mut cylces_taken = self.dispatch_opcode(x) as u32;
+                    self.total_cycles += cylces_taken as u64;
+
+                    // A DMA started by the instruction keeps the CPU off
+                    // the bus for a while.
+                    let stall = self.mem.take_stall_cycles(self.total_cycles % 2 == 1);
+                    self.total_cycles += stall as u64;
+                    cylces_taken += stall;
                     
                     self.previouspc = dummypc;
                     self.last_opcode = x;
@@ -123,9 +132,10 @@ impl Rico
                     // many cylces it needed. Note that dispatch opcode
                     // *must* modify PC itself, after the opcode
                     // has been dispatched.
-                    cycle_count += cylces_taken as u32;
+                    cycle_count += cylces_taken;
                 },
-                Err(_) => {
+                Err(e) => {
+                     self.log(format!("{}", e));
                      self.print_cpu_state();
                      self.logger.lock().unwrap().to_console();
                      panic!("Bad memory location read @ {:#2x}", self.pc); 
@@ -135,14 +145,26 @@ impl Rico
                                
         }
 
+        self.cycle_debt = cycle_count.saturating_sub(num_cycles);
+
         // This should do the trick for the PPU - the crappy
         // design decision to represent the PPU as a bit of
         // memory continues to haunt us.
-        let tick_res = self.mem.tick(num_cycles);    
-        match tick_res
+        // The devices get the cycles the instructions of this call actually
+        // took, in slices no longer than requested so the PPU still sees
+        // one scanline at a time. A long DMA stall runs them ahead, the
+        // following calls then run no instructions and tick nothing until
+        // the CPU has caught up.
+        let mut remaining = cycle_count - debt.min(cycle_count);
+        self.pending_irq = 0;
+        while remaining > 0
         {
-            MemTickResult::Ok => self.pending_irq = 0,
-            MemTickResult::IRQ(__irq) => self.pending_irq = __irq
+            let slice = remaining.min(num_cycles.max(1));
+            if let MemTickResult::IRQ(lines) = self.mem.tick(slice)
+            {
+                self.pending_irq |= lines;
+            }
+            remaining -= slice;
         }
 
         // Note: We have to make sure, that we avoid recursively
@@ -164,13 +186,29 @@ impl Rico
         self.log(format!("  .Last Successful op: {}({:#2x}) @ {:#2x}"     , self.last_opcode_nmonic, self.last_opcode, self.previouspc));   
     }
 
+    // All CPU stores go through here. A device refusing a write does not
+    // stop the CPU, unless the bus is set up to break on bad accesses.
+    fn write_mem(&mut self, address: usize, data: u8)
+    {
+        if let Err(e) = self.mem.write_byte(address, data)
+        {
+            self.log(format!("{}", e));
+            if self.mem.unmapped_policy() == UnmappedPolicy::Break
+            {
+                self.print_cpu_state();
+                self.logger.lock().unwrap().to_console();
+                panic!("Bad memory location written @ {:#2x}", address);
+            }
+        }
+    }
+
     fn prepare_irq(&mut self)
     {
         self.s = self.s | IRQ_DISABLE_MASK; 
         let write0 = self.s as usize;
         let write1 = (self.s - 1) as usize;
-        self.mem.write_byte(write0, (self.pc & 0xFF) as u8);
-        self.mem.write_byte(write1, ((self.pc & 0xFF00) >> 8) as u8);
+        self.write_mem(write0, (self.pc & 0xFF) as u8);
+        self.write_mem(write1, ((self.pc & 0xFF00) >> 8) as u8);
     }
 
     fn check_irq(&mut self)
@@ -180,9 +218,8 @@ impl Rico
             return;
         }
 
-        // irqs are currently disabled, so we don't do shit right now.
-        // ToDo: Check if the NMI can be disabled this way as well.
-        if (self.status & IRQ_DISABLE_MASK) == 1u8
+        // The I flag only masks IRQ, an NMI always gets through.
+        if (self.pending_irq & !IRQ_MSK) == 0 && (self.status & IRQ_DISABLE_MASK) != 0
         {
             return;
         }
@@ -558,13 +595,14 @@ impl Rico
 mod opcodetests 
 {
     use std::panic;
+    use std::rc::Rc;
     use crate::core6502::*;
     
     fn setup(opcode: u8) -> crate::core6502::Rico
     {
         let logger = Arc::new(Mutex::new(log::logger::new()));
         let mut m = RawMemory::new(0x8000);
-        m.write_byte(0x0000, opcode);
+        m.write_byte(0x0000, opcode).unwrap();
         let mut r = Rico::new(Box::new(m), logger);
         r.pc = 0x00;
         r.s = 0x00;
@@ -594,7 +632,7 @@ mod opcodetests
     fn adc_immediate_works_as_intended()
     {
         let mut cpu = setup(0x69);
-        cpu.mem.write_byte(0x0001, 44);
+        cpu.mem.write_byte(0x0001, 44).unwrap();
         cpu.a = 10;
         cpu.execute(1);
         assert_eq!(cpu.pc, 0x0002);
@@ -606,7 +644,7 @@ mod opcodetests
     fn adc_sets_zero_flag_if_zero()
     {
         let mut cpu = setup(0x69);
-        cpu.mem.write_byte(0x0001, 0);
+        cpu.mem.write_byte(0x0001, 0).unwrap();
         cpu.a = 0;
         cpu.execute(1);
         assert_eq!(cpu.a, 0);
@@ -617,7 +655,7 @@ mod opcodetests
     fn adc_sets_carry_flag_if_overflow()
     {        
         let mut cpu = setup(0x69);
-        cpu.mem.write_byte(0x0001, 0xFF);
+        cpu.mem.write_byte(0x0001, 0xFF).unwrap();
         cpu.a = 2;
         cpu.execute(1);
         assert_eq!(cpu.a, 1);
@@ -628,7 +666,7 @@ mod opcodetests
     fn adc_honors_carry_flag()
     {
         let mut cpu = setup(0x69);        
-        cpu.mem.write_byte(0x0001, 0x1);
+        cpu.mem.write_byte(0x0001, 0x1).unwrap();
         cpu.status = cpu.status | CARRY_MASK;
         cpu.a = 1;
         cpu.execute(1);
@@ -640,9 +678,9 @@ mod opcodetests
     fn adc_ind_works_as_intended()
     {
         let mut cpu = setup(0x6D);
-        cpu.mem.write_byte(0x0001, 0xCD);
-        cpu.mem.write_byte(0x0002, 0x7E);
-        cpu.mem.write_byte(0x7ECD, 0xAE);
+        cpu.mem.write_byte(0x0001, 0xCD).unwrap();
+        cpu.mem.write_byte(0x0002, 0x7E).unwrap();
+        cpu.mem.write_byte(0x7ECD, 0xAE).unwrap();
         cpu.execute(1);
         assert_eq!(cpu.a, 0xAE);        
     }
@@ -651,9 +689,9 @@ mod opcodetests
     fn adc_ind_indexed_x_works_as_intended()
     {
         let mut cpu = setup(0x7D);
-        cpu.mem.write_byte(0x0001, 0xCD);
-        cpu.mem.write_byte(0x0002, 0x7E);
-        cpu.mem.write_byte(0x7ECD + 0x20, 0xAE);
+        cpu.mem.write_byte(0x0001, 0xCD).unwrap();
+        cpu.mem.write_byte(0x0002, 0x7E).unwrap();
+        cpu.mem.write_byte(0x7ECD + 0x20, 0xAE).unwrap();
         cpu.x = 0x20;
         cpu.execute(1);
         assert_eq!(cpu.a, 0xAE);           
@@ -663,9 +701,9 @@ mod opcodetests
     fn adc_ind_indexed_y_works_as_intended()
     {
         let mut cpu = setup(0x79);
-        cpu.mem.write_byte(0x0001, 0xCD);
-        cpu.mem.write_byte(0x0002, 0x7E);
-        cpu.mem.write_byte(0x7ECD + 0x40, 0xAE);
+        cpu.mem.write_byte(0x0001, 0xCD).unwrap();
+        cpu.mem.write_byte(0x0002, 0x7E).unwrap();
+        cpu.mem.write_byte(0x7ECD + 0x40, 0xAE).unwrap();
         cpu.y = 0x40;
         cpu.execute(1);
         assert_eq!(cpu.a, 0xAE);           
@@ -675,8 +713,8 @@ mod opcodetests
     fn adc_zeropage_indexed_x_works_as_intended()
     {
         let mut cpu = setup(0x75);
-        cpu.mem.write_byte(0x0001, 0x7E); // offset at which to find the operand       
-        cpu.mem.write_byte(0x007E, 0x44); // actual operand
+        cpu.mem.write_byte(0x0001, 0x7E).unwrap(); // offset at which to find the operand       
+        cpu.mem.write_byte(0x007E, 0x44).unwrap(); // actual operand
         cpu.a = 0x20;
         cpu.execute(1);
         assert_eq!(cpu.a, 0x20 + 0x44);           
@@ -686,8 +724,8 @@ mod opcodetests
     fn adc_zeropage_indexed_works_as_intended()
     {
         let mut cpu = setup(0x65);
-        cpu.mem.write_byte(0x0001, 0x0F);
-        cpu.mem.write_byte(0x000F, 0x7E);        
+        cpu.mem.write_byte(0x0001, 0x0F).unwrap();
+        cpu.mem.write_byte(0x000F, 0x7E).unwrap();        
         cpu.x = 0x10;
         cpu.a = 0x20;
         cpu.execute(1);
@@ -698,10 +736,10 @@ mod opcodetests
     fn adc_indirect_x_indexed_works_as_intended()
     {
         let mut cpu = setup(0x61);
-        cpu.mem.write_byte(0x0001, 0x09);
-        cpu.mem.write_byte(0x000A, 0xAB);   // adr hi
-        cpu.mem.write_byte(0x000B, 0x0F);   // adr lo
-        cpu.mem.write_byte(0x0FAB, 0x20);   // adr lo
+        cpu.mem.write_byte(0x0001, 0x09).unwrap();
+        cpu.mem.write_byte(0x000A, 0xAB).unwrap();   // adr hi
+        cpu.mem.write_byte(0x000B, 0x0F).unwrap();   // adr lo
+        cpu.mem.write_byte(0x0FAB, 0x20).unwrap();   // adr lo
         cpu.x = 0x01;
         cpu.a = 0x20;
         cpu.execute(1);
@@ -712,9 +750,9 @@ mod opcodetests
     fn adc_indirect_y_postindexed_works_as_intended()
     {
         let mut cpu = setup(0x71);
-        cpu.mem.write_byte(0x0001, 0x10);
-        cpu.mem.write_byte(0x0002, 0x09);   
-        cpu.mem.write_byte(0x0930, 0xAB);
+        cpu.mem.write_byte(0x0001, 0x10).unwrap();
+        cpu.mem.write_byte(0x0002, 0x09).unwrap();   
+        cpu.mem.write_byte(0x0930, 0xAB).unwrap();
         cpu.y = 0x20;
         cpu.a = 0x10;
         cpu.execute(1);
@@ -727,7 +765,7 @@ mod opcodetests
     fn sbc_works_as_intended()
     {
        let mut cpu = setup(0xE9); 
-       cpu.mem.write_byte(0x0001, 22);
+       cpu.mem.write_byte(0x0001, 22).unwrap();
        cpu.a = 27;
        cpu.execute(1);
        assert_eq!(cpu.a, 5);
@@ -738,7 +776,7 @@ mod opcodetests
     fn sbc_sets_carry_if_underflow()
     {
         let mut cpu = setup(0xE9); 
-        cpu.mem.write_byte(0x0001, 27);
+        cpu.mem.write_byte(0x0001, 27).unwrap();
         cpu.a = 22;
         cpu.execute(1);
         assert_eq!(cpu.a, 5);
@@ -819,7 +857,7 @@ mod opcodetests
     fn lda_loads_accumulator()
     {
         let mut cpu = setup(0xa9);
-        cpu.mem.write_byte(0x0001, 0x10);
+        cpu.mem.write_byte(0x0001, 0x10).unwrap();
         cpu.execute(1);
         assert_eq!(cpu.a, 0x10);
     }
@@ -828,7 +866,7 @@ mod opcodetests
     fn ldx_loads_x_reg()
     {
         let mut cpu = setup(0xa2);
-        cpu.mem.write_byte(0x0001, 0x10);
+        cpu.mem.write_byte(0x0001, 0x10).unwrap();
         cpu.execute(1);
         assert_eq!(cpu.x, 0x10);       
     }
@@ -837,7 +875,7 @@ mod opcodetests
     fn ldy_loads_y_reg()
     {
         let mut cpu = setup(0xa0);
-        cpu.mem.write_byte(0x0001, 0x10);
+        cpu.mem.write_byte(0x0001, 0x10).unwrap();
         cpu.execute(1);
         assert_eq!(cpu.y, 0x10);       
     }
@@ -846,10 +884,10 @@ mod opcodetests
     fn ldx_indexed_x_loads_x()
     {
         let mut cpu = setup(0xbd);
-        cpu.mem.write_byte(0x0001, 0x11);
-        cpu.mem.write_byte(0x0002, 0x12);
+        cpu.mem.write_byte(0x0001, 0x11).unwrap();
+        cpu.mem.write_byte(0x0002, 0x12).unwrap();
         cpu.x = 0x10;
-        cpu.mem.write_byte(0x1221, 0xAB);
+        cpu.mem.write_byte(0x1221, 0xAB).unwrap();
         cpu.execute(1);
         assert_eq!(cpu.x, 0xAB);       
     }
@@ -867,7 +905,7 @@ mod opcodetests
     fn cmp_sets_carry_if_comparand_is_smaller()
     {
         let mut cpu = setup(0xc9);
-        cpu.mem.write_byte(0x0001, 0x11);       
+        cpu.mem.write_byte(0x0001, 0x11).unwrap();       
         cpu.a = 0x21;
         cpu.execute(1);
         assert_eq!(cpu.status & CARRY_MASK, CARRY_MASK);
@@ -877,7 +915,7 @@ mod opcodetests
     fn cmp_sets_zero_if_comparand_is_equal()
     {
         let mut cpu = setup(0xc9);
-        cpu.mem.write_byte(0x0001, 0x11);       
+        cpu.mem.write_byte(0x0001, 0x11).unwrap();       
         cpu.a = 0x11;
         cpu.execute(1);
         assert_eq!(cpu.status & ZERO_MASK, ZERO_MASK);
@@ -887,7 +925,7 @@ mod opcodetests
     fn cmp_sets_neg_if_comparand_is_larger()
     {
         let mut cpu = setup(0xc9);
-        cpu.mem.write_byte(0x0001, 0x21);       
+        cpu.mem.write_byte(0x0001, 0x21).unwrap();       
         cpu.a = 0x11;
         cpu.execute(1);
         assert_eq!(cpu.status & NEG_MASK, NEG_MASK);
@@ -897,19 +935,112 @@ mod opcodetests
     fn stx_works()
     {
         let mut cpu = setup(0x86);
-        cpu.mem.write_byte(0x0001, 0x24);
+        cpu.mem.write_byte(0x0001, 0x24).unwrap();
         cpu.x = 0xFA;
         cpu.execute(1);
         assert_eq!(true, has_value_at(&mut cpu, 0x24, 0xFA))
     }
 
+    fn setup_with_oam_dma() -> crate::core6502::Rico
+    {
+        let logger = Arc::new(Mutex::new(log::logger::new()));
+        let fb = Rc::new(RefCell::new(vec![0u32; 256 * 240]));
+        let mut ram = RawMemory::new(0x0800);
+        ram.write_byte(0x0000, 0x8D).unwrap();   // STA $4014
+        ram.write_byte(0x0001, 0x14).unwrap();
+        ram.write_byte(0x0002, 0x40).unwrap();
+
+        let mut m = CompositeMemory::new();
+        m.set_unmapped_policy(UnmappedPolicy::Silent);
+        m.register_mirrored_range(0x0000, 0x1FFF, 0x0800, Box::new(ram)).unwrap();
+        m.register_mirrored_range(0x2000, 0x3FFF, 0x0008, Box::new(crate::ppu::ppu::new(logger.clone(), fb))).unwrap();
+        m.register_range(0x4014, 0x4014, Box::new(crate::dma::SpriteDMA::new())).unwrap();
+
+        let mut r = Rico::new(Box::new(m), logger);
+        r.pc = 0x00;
+        r.a = 0x03;
+        r
+    }
+
+    #[test]
+    fn oam_dma_stalls_cpu_for_513_cycles_on_even_cycle()
+    {
+        let mut cpu = setup_with_oam_dma();
+        cpu.execute(1);
+        assert_eq!(4 + 513, cpu.total_cycles);
+    }
+
+    #[test]
+    fn oam_dma_stalls_cpu_for_514_cycles_on_odd_cycle()
+    {
+        let mut cpu = setup_with_oam_dma();
+        cpu.total_cycles = 1;
+        cpu.execute(1);
+        assert_eq!(1 + 4 + 514, cpu.total_cycles);
+    }
+
+    #[test]
+    fn refused_write_is_logged_and_execution_continues()
+    {
+        let logger = Arc::new(Mutex::new(log::logger::new()));
+        let fb = Rc::new(RefCell::new(vec![0u32; 256 * 240]));
+        let mut ram = RawMemory::new(0x0800);
+        ram.write_byte(0x0000, 0x8D).unwrap();   // STA $2002
+        ram.write_byte(0x0001, 0x02).unwrap();
+        ram.write_byte(0x0002, 0x20).unwrap();
+
+        let mut m = CompositeMemory::new();
+        m.set_unmapped_policy(UnmappedPolicy::Log);
+        m.register_mirrored_range(0x0000, 0x1FFF, 0x0800, Box::new(ram)).unwrap();
+        m.register_mirrored_range(0x2000, 0x3FFF, 0x0008, Box::new(crate::ppu::ppu::new(logger.clone(), fb))).unwrap();
+
+        let mut cpu = Rico::new(Box::new(m), logger.clone());
+        cpu.pc = 0x00;
+        let logged = logger.lock().unwrap().len();
+        cpu.execute(1);
+
+        assert_eq!(0x0003, cpu.pc);
+        assert!(logger.lock().unwrap().len() > logged);
+    }
+
+    // Runs 114 cycle slices like main does. The DMA is started by the STA
+    // at $0000, the NOPs from $0003 on show when the CPU runs again.
+    fn pc_after_slices(cpu: &mut crate::core6502::Rico, slices: u32) -> u16
+    {
+        for _ in 0..slices
+        {
+            cpu.execute(114);
+        }
+        cpu.pc
+    }
+
+    #[test]
+    fn oam_dma_stall_carries_over_into_following_slices()
+    {
+        let mut cpu = setup_with_oam_dma();
+
+        // 4 + 513 cycles are 4 full slices and 61 cycles of the fifth.
+        assert_eq!(0x0003, pc_after_slices(&mut cpu, 4));
+        assert_eq!(0x0003 + 114 * 5 - 4 - 513, pc_after_slices(&mut cpu, 1));
+    }
+
+    #[test]
+    fn oam_dma_stall_on_odd_cycle_carries_over_one_more_cycle()
+    {
+        let mut cpu = setup_with_oam_dma();
+        cpu.total_cycles = 1;
+
+        assert_eq!(0x0003, pc_after_slices(&mut cpu, 4));
+        assert_eq!(0x0003 + 114 * 5 - 4 - 514, pc_after_slices(&mut cpu, 1));
+    }
+
     #[test]
     fn lda_ll_y()
     {
         let mut cpu = setup(0xB1);
-        cpu.mem.write_byte(0x0001, 0x22 );
-        cpu.mem.write_byte(0x0002, 0x77 );
-        cpu.mem.write_byte(0x7732, 15);
+        cpu.mem.write_byte(0x0001, 0x22 ).unwrap();
+        cpu.mem.write_byte(0x0002, 0x77 ).unwrap();
+        cpu.mem.write_byte(0x7732, 15).unwrap();
         cpu.a = 0;
         cpu.y = 0x10;
         cpu.execute(1);
@@ -161,8 +161,8 @@ impl<'a> LoadResult<'a>
         cpu.pc = self.val;
         let write0 = cpu.s as usize;
         let write1 = (cpu.s - 1) as usize;
-        cpu.mem.write_byte(write0, (nextpc & 0xFF) as u8);
-        cpu.mem.write_byte(write1, ((nextpc & 0xFF00) >> 8) as u8);
+        cpu.write_mem(write0, (nextpc & 0xFF) as u8);
+        cpu.write_mem(write1, ((nextpc & 0xFF00) >> 8) as u8);
         cpu.s -= 2;
         drop(cpu);
         
@@ -173,7 +173,7 @@ impl<'a> LoadResult<'a>
     {
         let mut cpu = self.origin.cpu.borrow_mut();
         let write0 = cpu.s as usize;
-        cpu.mem.write_byte(write0, self.val as u8);
+        cpu.write_mem(write0, self.val as u8);
         cpu.s -= 1;
         drop(cpu);
         self.origin
@@ -254,7 +254,7 @@ impl<'a> LoadResult<'a>
     {
         let mut cpu = self.origin.cpu.borrow_mut();
         let memval = cpu.mem.read_byte(self.val as usize).unwrap() + inc;
-        cpu.mem.write_byte(self.val as usize, memval);
+        cpu.write_mem(self.val as usize, memval);
         drop(cpu);
         self.origin
     }
@@ -355,7 +355,7 @@ impl<'a> StoreCommand<'a>
         let mut cpu = self.origin.cpu.borrow_mut();
         let read_adr = (cpu.pc + 1) as usize;
         let adr = cpu.mem.read_u16(read_adr).unwrap() as usize;                
-        cpu.mem.write_byte(adr, self.val as u8);
+        cpu.write_mem(adr, self.val as u8);
         drop(cpu);
         let logstring = format!("       #({}) -> #({})", self.val as u8, adr);
         self.log(logstring);
@@ -374,7 +374,7 @@ impl<'a> StoreCommand<'a>
         let read_adr = (cpu.pc + 1) as usize;
         let adr = cpu.mem.read_byte(read_adr).unwrap() as usize;
         let logstring = format!("          #({}) -> {:#4x}", self.val as u8, adr);
-        cpu.mem.write_byte(adr, self.val as u8);
+        cpu.write_mem(adr, self.val as u8);
         drop(cpu);
         self.log(logstring);
         
@@ -397,7 +397,7 @@ impl<'a> StoreCommand<'a>
         let store1 = cpu.mem.read_byte((pc + 1) as usize).unwrap() as u16;
         let store_base = (store1 << 8) + store0;
         let store_add = store_base + store_addition as u16;
-        cpu.mem.write_byte(store_add as usize, self.val as u8);
+        cpu.write_mem(store_add as usize, self.val as u8);
         drop(cpu);
         let logstring = format!("           #({}) -> ({:#4x} + {}({}))", self.val, store_base, store_addition, indirection);
         self.log(logstring);        
@@ -410,7 +410,7 @@ impl<'a> StoreCommand<'a>
         let mut cpu = self.origin.cpu.borrow_mut();
         let pc = cpu.pc +1 as u16;
         let target_base = cpu.mem.read_u16(pc as usize).unwrap() + store_addition;
-        cpu.mem.write_byte(target_base as usize, self.val as u8);
+        cpu.write_mem(target_base as usize, self.val as u8);
         drop(cpu);
         let logstring = format!("           #({}) -> ({:#4x} + {}({}))", self.val, target_base, store_addition, indirection);
         self.log(logstring);        
@@ -800,8 +800,8 @@ mod store_command_tests
     {
         run_test(0xAB, |sr|
         {
-            sr.origin.cpu.borrow_mut().mem.write_byte(0x8001, 0x20);
-            sr.origin.cpu.borrow_mut().mem.write_byte(0x8002, 0x10);
+            sr.origin.cpu.borrow_mut().mem.write_byte(0x8001, 0x20).unwrap();
+            sr.origin.cpu.borrow_mut().mem.write_byte(0x8002, 0x10).unwrap();
             let oc = sr.to_immediate_address();
             check_has_val_at(&oc, 0x1020, 0xAB)            
         })
@@ -1,41 +1,144 @@
-use crate::memory::{Memory, MemTickResult};
-use std::cell::RefCell;
-use std::rc::Rc;
+use crate::memory::{BusRequest, Memory, MemTickResult};
 
+// OAM DMA register at $4014. Writing a page number does not copy anything
+// by itself, it asks the bus to do the transfer once the write is done.
 pub struct SpriteDMA
 {
-    ram: Rc<RefCell<crate::memory::CompositeMemory>>
+    pending_page: Option<u8>
 }
 
 impl SpriteDMA
 {
-    pub fn new(ram: Rc<RefCell<crate::memory::CompositeMemory>>) -> Self
+    pub fn new() -> Self
     {
-        Self {ram}
+        Self {pending_page: None}
     }
 }
 
 impl Memory for SpriteDMA
 {
-    fn read_byte(&mut self, address: usize) -> Result<u8, crate::memory::MemError> {
-        todo!()
+    fn read_byte(&mut self, _address: usize) -> Result<u8, crate::memory::MemError> {
+        // Write only, the value comes from the open bus.
+        Ok(0x00)
     }
 
-    fn write_byte(&mut self, address: usize, data: u8) -> crate::memory::MemError {
-        let data_source_address = data as u16 * 0x100 as u16;
-        let mut mem = self.ram.borrow_mut();
+    fn write_byte(&mut self, _address: usize, data: u8) -> Result<(), crate::memory::MemError> {
+        self.pending_page = Some(data);
+        Ok(())
+    }
+
+    fn peek(&self, _address: usize) -> Result<u8, crate::memory::MemError> {
+        Ok(0x00)
+    }
+
+    fn open_bus_mask(&self, _address: usize) -> u8 {
+        0xFF
+    }
+
+    fn poke(&mut self, _address: usize, _data: u8) -> Result<(), crate::memory::MemError> {
+        // Poking the register must not start a transfer.
+        Ok(())
+    }
+
+    fn take_bus_request(&mut self) -> Option<BusRequest> {
+        self.pending_page.take().map(BusRequest::OamDma)
+    }
+
+    fn tick(&mut self, _clock_ticks: u32) -> crate::memory::MemTickResult {
+        MemTickResult::Ok
+    }
+
+    fn name(&self) -> &'static str {
+        "SpriteDMA"
+    }
+}
+
+#[cfg(test)]
+mod dmatests
+{
+    use crate::dma::*;
+    use crate::memory::*;
+    use crate::{log, ppu};
+    use std::{cell::RefCell, rc::Rc};
+    use std::sync::{Arc, Mutex};
+
+    fn setup() -> CompositeMemory
+    {
+        let fb = Rc::new(RefCell::new(vec![0u32; 256 * 240]));
+        let p = ppu::ppu::new(Arc::new(Mutex::new(log::logger::new())), fb);
+        let mut ram = RawMemory::new(0x0800);
         for i in 0..256
         {
-            if let Ok(source_byte) = mem.read_byte((data_source_address + i) as usize)
-            {
-                mem.write_byte(0x2004, source_byte);
-            }
-            
+            ram.write_byte(0x0200 + i, i as u8 ^ 0x5A).unwrap();
         }
-        crate::memory::MemError::Ok
+
+        let mut m = CompositeMemory::new();
+        m.register_mirrored_range(0x0000, 0x1FFF, 0x0800, Box::new(ram)).unwrap();
+        m.register_mirrored_range(0x2000, 0x3FFF, 0x0008, Box::new(p)).unwrap();
+        m.register_range(0x4014, 0x4014, Box::new(SpriteDMA::new())).unwrap();
+        m
     }
 
-    fn tick(&mut self, _clock_ticks: u32) -> crate::memory::MemTickResult {
-        MemTickResult::Ok
+    fn oam_at(m: &mut CompositeMemory, index: u8) -> u8
+    {
+        m.poke(0x2003, index).unwrap();
+        m.peek(0x2004).unwrap()
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn write_to_4014_copies_page_to_oam()
+    {
+        let mut m = setup();
+
+        assert_eq!(Ok(()), m.write_byte(0x4014, 0x02));
+
+        for i in 0..=255u8
+        {
+            assert_eq!(i ^ 0x5A, oam_at(&mut m, i));
+        }
+    }
+
+    #[test]
+    fn oam_dma_starts_at_oamaddr()
+    {
+        let mut m = setup();
+        m.write_byte(0x2003, 0x10).unwrap();
+
+        m.write_byte(0x4014, 0x02).unwrap();
+
+        assert_eq!(0x5A, oam_at(&mut m, 0x10));
+        assert_eq!(0xFF ^ 0x5A, oam_at(&mut m, 0x0F));
+    }
+
+    #[test]
+    fn oam_dma_stalls_cpu_depending_on_alignment()
+    {
+        let mut m = setup();
+        assert_eq!(0, m.take_stall_cycles(false));
+
+        m.write_byte(0x4014, 0x02).unwrap();
+        assert_eq!(513, m.take_stall_cycles(false));
+        assert_eq!(0, m.take_stall_cycles(false));
+
+        m.write_byte(0x4014, 0x02).unwrap();
+        assert_eq!(514, m.take_stall_cycles(true));
+    }
+
+    #[test]
+    fn poke_does_not_start_transfer()
+    {
+        let mut dma = SpriteDMA::new();
+        dma.poke(0x00, 0x02).unwrap();
+        assert_eq!(None, dma.take_bus_request());
+    }
+
+    #[test]
+    fn reading_4014_returns_open_bus()
+    {
+        let mut m = setup();
+        m.write_byte(0x0000, 0x3C).unwrap();
+        m.read_byte(0x0000).unwrap();
+
+        assert_eq!(0x3C, m.read_byte(0x4014).unwrap());
+    }
+}
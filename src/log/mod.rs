@@ -17,6 +17,12 @@ impl logger{
         self.buf.add(message);
     }
 
+    #[cfg(test)]
+    pub fn len(&self) -> usize
+    {
+        self.buf.size()
+    }
+
     pub fn to_console(&mut self)
     {
         let mut done: bool = false;
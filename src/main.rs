@@ -3,89 +3,20 @@ mod memory;
 mod ppu;
 mod log;
 mod dma;
+mod cartridge;
+mod mapper;
 
 extern crate minifb;
 
 use minifb::{Key, Window, WindowOptions};
 
-use std::io::{self, BufReader, Read};
-use std::fs::{self};
-
 use std::sync::Mutex;
-use std::{slice, sync::Arc, cell::RefCell, rc::Rc};
-
-// Evil hack of doom!
-impl memory::Memory for std::rc::Rc<std::cell::RefCell<memory::CompositeMemory>>
-{
-    fn read_byte(&mut self, address: usize) -> Result<u8, memory::MemError> {
-        (*self.borrow_mut()).read_byte(address)
-    }
-
-    fn write_byte(&mut self, address: usize, data: u8) -> memory::MemError {
-
-        if address == 0x4014
-        {
-            println!("write to SPR-DMA");
-        }
-
-        (*self.borrow_mut()).write_byte(address, data)
-    }
-
-    fn tick(&mut self, _clock_ticks: u32) -> memory::MemTickResult {
-        (*self.borrow_mut()).tick(_clock_ticks)
-    }
-}
-
-struct INESHeader
-{
-    const_data: [u8; 4],     // always "NES/0x1A"
-    prog_rom_banks: u8,       // in 16 KiB units
-    charRomBanks: u8,       // in 8 KiB units
-    flagBytes: [u8; 10]
-}
-
-fn read_struct<T, R: Read>(read: &mut R) -> io::Result<T> {
-    let num_bytes = ::std::mem::size_of::<T>();
-    unsafe {
-        let mut s = ::std::mem::uninitialized();
-        let buffer = slice::from_raw_parts_mut(&mut s as *mut T as *mut u8, num_bytes);
-        match read.read_exact(buffer) {
-            Ok(()) => Ok(s),
-            Err(e) => {
-                ::std::mem::forget(s);
-                Err(e)
-            }
-        }
-    }
-}
-
-fn load_rom(romfile: String, targetMemory: &mut dyn memory::Memory)
-{
-    println!("Open {} ", romfile);
-    let file = fs::File::open(romfile);
-    let mut rd = BufReader::new(file.unwrap());
-    let res = read_struct::<INESHeader,_>(&mut rd).unwrap();    
-    println!("Has {} PRG ROM banks", res.prog_rom_banks);
-        
-    let mut prg_buf : [u8; 16384] = [0x00; 16384];
-
-    let mut x: u32;
-    for x in 0..res.prog_rom_banks
-    {        
-        rd.read_exact(&mut prg_buf);
-        let mut i: u32 = 0;
-        while i < 16384
-        {
-            let actualadr = (i + (x as u32) * 16384) as usize;
-            targetMemory.write_byte(actualadr, prg_buf[i as usize]);
-            i += 1;
-        }
-    }
-
-}
+use std::{sync::Arc, cell::RefCell, rc::Rc};
 
 const WIDTH: usize = 320;
 const HEIGHT: usize = 240;
+// Battery saves are written every 5 seconds at most.
+const SAVE_INTERVAL_FRAMES: u32 = 300;
 
 fn make_window() -> Window
 {
@@ -104,25 +35,78 @@ fn make_window() -> Window
 
 fn main() 
 {
-    let fb = Arc::new(RefCell::new(vec![0u32; WIDTH * HEIGHT]));
+    let fb = Rc::new(RefCell::new(vec![0u32; WIDTH * HEIGHT]));
 
     let mut window = make_window();
 
+    // NOVANES_POWER_ON=zeros|ones|alternating|random|<seed>, the seed
+    // printed below repeats a run with the same power on RAM.
+    let power_on = std::env::var("NOVANES_POWER_ON").ok()
+                        .and_then(|x| memory::PowerOnState::parse(&x))
+                        .unwrap_or_else(memory::PowerOnState::random);
+    println!("Power on RAM state: {:?}", power_on);
+
     let logger = Arc::new(Mutex::new(log::logger::new()));
-    let ppu = ppu::ppu::new(logger.clone(), fb.clone());
-    let ram = memory::RawMemory::new(0x2000);
-    let mut m = memory::RawMemory::new(0x8000);
-    load_rom("./roms/smb1.nes".to_string(), &mut m);
+    let mut ppu = ppu::ppu::new(logger.clone(), fb.clone());
+    ppu.power_on(power_on);
+    let ram = memory::RawMemory::with_power_on(0x0800, power_on);
+    let romfile = "./roms/smb1.nes";
+    println!("Open {} ", romfile);
+    let cart = cartridge::Cartridge::load(romfile).unwrap_or_else(|e| {
+        panic!("{}", e);
+    });
+    println!("{:?} header, {} KiB PRG ROM, {} KiB CHR ROM, mapper {}.{}, {:?} mirroring, {:?}, {:?}, battery: {}, trainer: {}",
+             cart.format, cart.prg_rom.len() / 1024, cart.chr_rom.len() / 1024, cart.mapper, cart.submapper,
+             cart.mirroring, cart.region, cart.console, cart.battery, cart.trainer.is_some());
+    println!("PRG RAM {} bytes, PRG NVRAM {} bytes, CHR RAM {} bytes, CHR NVRAM {} bytes",
+             cart.prg_ram_size, cart.prg_nvram_size, cart.chr_ram_size, cart.chr_nvram_size);
+
+    let mut save = if cart.battery { Some(cartridge::save::SaveFile::for_rom(romfile)) } else { None };
+    let mapper = mapper::create(cart, power_on).unwrap_or_else(|e| {
+        panic!("{}", e);
+    });
+    println!("Mapper: {}", mapper.borrow().name());
+    if let Some(save) = &mut save
+    {
+        match save.load(mapper.borrow_mut().prg_ram())
+        {
+            Ok(true) => println!("Loaded {}", save.path().display()),
+            Ok(false) => println!("No save file {} yet", save.path().display()),
+            Err(e) => panic!("{}", e)
+        }
+    }
+    ppu.set_mapper(mapper.clone());
+
+    // NOVANES_UNMAPPED=silent|log|break, what to do about accesses no
+    // device answers.
+    let unmapped_policy = std::env::var("NOVANES_UNMAPPED").ok()
+                              .and_then(|x| memory::UnmappedPolicy::parse(&x))
+                              .unwrap_or(memory::UnmappedPolicy::Log);
+
     let mut memmap = memory::CompositeMemory::new();
+    memmap.set_unmapped_policy(unmapped_policy);
+    memmap.set_logger(logger.clone());
 
     // ToDo: Add peripherals as ranges as well.
-    memmap.register_range(0x0000, 0x1FFF, Box::new(ram));
-    memmap.register_range(0x8000, 0x8000 + 0x8000, Box::new(m));
-    memmap.register_range(0x2000, 0x2000 + 0x0008, Box::new(ppu));    
-    
-    let memmorycell = Rc::new(RefCell::new(memmap));
-    memmorycell.borrow_mut().register_range(0x4014, 0x4014, Box::new(dma::SpriteDMA::new(memmorycell.clone())));    
-    let mut core = core6502::Rico::new(Box::new(memmorycell.clone()), logger.clone());
+    memmap.register_mirrored_range(0x0000, 0x1FFF, 0x0800, Box::new(ram)).unwrap();
+    memmap.register_range(0x4020, 0xFFFF, Box::new(mapper::CpuPort::new(mapper.clone()))).unwrap();
+    memmap.register_mirrored_range(0x2000, 0x3FFF, 0x0008, Box::new(ppu)).unwrap();
+    memmap.register_range(0x4014, 0x4014, Box::new(dma::SpriteDMA::new())).unwrap();
+    println!("{}", memmap.describe_map());
+
+    // NOVANES_BREAK=<hex address> logs every opcode fetch from there.
+    if let Some(address) = std::env::var("NOVANES_BREAK").ok()
+                               .and_then(|x| usize::from_str_radix(x.trim_start_matches('$'), 16).ok())
+    {
+        let breakpoint_logger = logger.clone();
+        memmap.add_hook(address, address, memory::HookKind::Exec, Box::new(move |_, _| {
+            breakpoint_logger.lock().unwrap().write(format!("Breakpoint hit @ {:#06x}.", address));
+            None
+        }));
+    }
+
+    let mut core = core6502::Rico::new(Box::new(memmap), logger.clone());
+    let mut frames: u32 = 0;
 
     while window.is_open() && !window.is_key_down(Key::Escape) 
     {
@@ -140,6 +124,25 @@ fn main()
         window
             .update_with_buffer(fb.borrow().as_slice(), WIDTH, HEIGHT)
             .unwrap();
+
+        frames += 1;
+        if frames == SAVE_INTERVAL_FRAMES
+        {
+            flush_save(&mut save, &mapper);
+            frames = 0;
+        }
     }
 
+    flush_save(&mut save, &mapper);
+}
+
+fn flush_save(save: &mut Option<cartridge::save::SaveFile>, mapper: &mapper::SharedMapper)
+{
+    if let Some(save) = save
+    {
+        if let Err(e) = save.flush(mapper.borrow_mut().prg_ram())
+        {
+            println!("{}", e);
+        }
+    }
 }
\ No newline at end of file
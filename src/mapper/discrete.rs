@@ -0,0 +1,264 @@
+use crate::memory::*;
+use crate::cartridge::{Cartridge, CartridgeError, Mirroring};
+use crate::mapper::{Chr, Mapper};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x2000;
+
+const AXROM_SCREEN_B: u8 = 0b00010000;
+
+// Boards that are built from a latch and a few logic chips. A write to
+// $8000-$FFFF stores the data byte in the latch, that's all.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Board
+{
+    // Mapper 2: 16 KiB bank at $8000, last bank fixed at $C000.
+    Uxrom,
+    // Mapper 3: fixed PRG like NROM, 8 KiB CHR bank.
+    Cnrom,
+    // Mapper 7: 32 KiB PRG bank, bit 4 selects the single screen.
+    Axrom,
+    // Mapper 66: PRG bank in bits 4-5, CHR bank in bits 0-1.
+    Gxrom
+}
+
+// Since the ROM is not disabled during writes, both drive the data bus and
+// the latch sees the AND of the written value and the ROM byte at that
+// address. Games on such boards write to a location that already holds the
+// value. Whether a board has this depends on its revision, so it is a flag.
+pub struct Discrete
+{
+    board: Board,
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    latch: u8,
+    mirroring: Mirroring,
+    bus_conflicts: bool
+}
+
+impl Discrete
+{
+    // UxROM counts its fixed bank back from the end of PRG ROM, so it needs
+    // at least one full bank.
+    pub fn new(board: Board, cart: Cartridge, state: PowerOnState, bus_conflicts: bool) -> Result<Self, CartridgeError>
+    {
+        if board == Board::Uxrom && cart.prg_rom.len() < PRG_BANK_SIZE
+        {
+            return Err(CartridgeError::TooSmall { section: "PRG ROM", minimum: PRG_BANK_SIZE, found: cart.prg_rom.len() });
+        }
+
+        // AxROM is always single screen, it powers up with the latch cleared.
+        let mirroring = if board == Board::Axrom { Mirroring::SingleScreenA } else { cart.mirroring };
+
+        Ok(Discrete {
+            board,
+            prg_rom: cart.prg_rom,
+            chr: Chr::new(cart.chr_rom, cart.chr_ram_size, state),
+            latch: 0,
+            mirroring,
+            bus_conflicts
+        })
+    }
+
+    // Offset into PRG ROM for a CPU address in $8000-$FFFF.
+    fn prg_offset(&self, address: usize) -> usize
+    {
+        let offset = match self.board
+        {
+            Board::Uxrom => {
+                let last = self.prg_rom.len() / PRG_BANK_SIZE - 1;
+                let bank = if address >= 0xC000 { last } else { self.latch as usize };
+                bank * PRG_BANK_SIZE + (address & 0x3FFF)
+            },
+            Board::Cnrom => address - 0x8000,
+            Board::Axrom => (self.latch & 0x07) as usize * 2 * PRG_BANK_SIZE + (address - 0x8000),
+            Board::Gxrom => ((self.latch >> 4) & 0x03) as usize * 2 * PRG_BANK_SIZE + (address - 0x8000)
+        };
+        offset % self.prg_rom.len()
+    }
+
+    fn chr_offset(&self, address: usize) -> usize
+    {
+        let bank = match self.board
+        {
+            Board::Cnrom => self.latch as usize,
+            Board::Gxrom => (self.latch & 0x03) as usize,
+            _ => 0
+        };
+        bank * CHR_BANK_SIZE + address
+    }
+}
+
+impl Mapper for Discrete
+{
+    fn cpu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        if address < 0x8000
+        {
+            return Ok(());
+        }
+
+        self.latch = if self.bus_conflicts { data & self.prg_rom[self.prg_offset(address)] } else { data };
+        if self.board == Board::Axrom
+        {
+            self.mirroring = if self.latch & AXROM_SCREEN_B != 0 { Mirroring::SingleScreenB } else { Mirroring::SingleScreenA };
+        }
+        Ok(())
+    }
+
+    fn cpu_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        if address < 0x8000
+        {
+            return Ok(0x00);
+        }
+        Ok(self.prg_rom[self.prg_offset(address)])
+    }
+
+    fn cpu_open_bus_mask(&self, address: usize) -> u8
+    {
+        if address < 0x8000 { 0xFF } else { 0x00 }
+    }
+
+    fn ppu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        let offset = self.chr_offset(address);
+        self.chr.write(offset, data);
+        Ok(())
+    }
+
+    fn ppu_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        Ok(self.chr.read(self.chr_offset(address)))
+    }
+
+    fn mirroring(&self) -> Mirroring
+    {
+        self.mirroring
+    }
+
+    fn name(&self) -> &'static str
+    {
+        match self.board
+        {
+            Board::Uxrom => "UxROM",
+            Board::Cnrom => "CNROM",
+            Board::Axrom => "AxROM",
+            Board::Gxrom => "GxROM"
+        }
+    }
+}
+
+#[cfg(test)]
+mod discretetests
+{
+    use crate::mapper::discrete::*;
+    use crate::mapper::test_rom;
+
+    // The board is picked here, the mapper number in the header does not
+    // matter.
+    fn setup(board: Board, prg_banks: usize, chr_banks: usize, bus_conflicts: bool) -> Discrete
+    {
+        let cart = test_rom(0, PRG_BANK_SIZE, prg_banks, CHR_BANK_SIZE, chr_banks);
+        Discrete::new(board, cart, PowerOnState::Zeros, bus_conflicts).unwrap()
+    }
+
+    #[test]
+    fn uxrom_switches_8000_and_fixes_last_bank()
+    {
+        let mut m = setup(Board::Uxrom, 8, 0, false);
+        m.cpu_write(0x8000, 3).unwrap();
+
+        assert_eq!(3, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(3, m.cpu_peek(0xBFFF).unwrap());
+        assert_eq!(7, m.cpu_peek(0xC000).unwrap());
+        assert_eq!(7, m.cpu_peek(0xFFFF).unwrap());
+    }
+
+    #[test]
+    fn uxrom_rejects_prg_rom_smaller_than_fixed_bank()
+    {
+        let mut cart = test_rom(2, PRG_BANK_SIZE, 1, CHR_BANK_SIZE, 0);
+        cart.prg_rom.truncate(0x2000);
+
+        match Discrete::new(Board::Uxrom, cart, PowerOnState::Zeros, false)
+        {
+            Err(e) => assert_eq!(CartridgeError::TooSmall { section: "PRG ROM", minimum: 0x4000, found: 0x2000 }, e),
+            Ok(_) => panic!("UxROM with 8 KiB PRG ROM was accepted")
+        }
+    }
+
+    #[test]
+    fn uxrom_has_chr_ram()
+    {
+        let mut m = setup(Board::Uxrom, 2, 0, false);
+        m.ppu_write(0x0123, 0x45).unwrap();
+
+        assert_eq!(0x45, m.ppu_peek(0x0123).unwrap());
+    }
+
+    #[test]
+    fn cnrom_switches_chr_bank()
+    {
+        let mut m = setup(Board::Cnrom, 2, 4, false);
+        m.cpu_write(0x8000, 2).unwrap();
+
+        assert_eq!(2, m.ppu_peek(0x0000).unwrap());
+        assert_eq!(2, m.ppu_peek(0x1FFF).unwrap());
+        assert_eq!(0, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(1, m.cpu_peek(0xC000).unwrap());
+    }
+
+    #[test]
+    fn cnrom_128_mirrors_prg()
+    {
+        let m = setup(Board::Cnrom, 1, 1, false);
+
+        assert_eq!(0, m.cpu_peek(0xC000).unwrap());
+    }
+
+    #[test]
+    fn axrom_switches_32k_and_screen()
+    {
+        let mut m = setup(Board::Axrom, 8, 0, false);
+        assert_eq!(Mirroring::SingleScreenA, m.mirroring());
+
+        m.cpu_write(0x8000, AXROM_SCREEN_B | 2).unwrap();
+
+        assert_eq!(4, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(5, m.cpu_peek(0xC000).unwrap());
+        assert_eq!(Mirroring::SingleScreenB, m.mirroring());
+    }
+
+    #[test]
+    fn gxrom_switches_prg_and_chr()
+    {
+        let mut m = setup(Board::Gxrom, 8, 4, false);
+        m.cpu_write(0x8000, 0x31).unwrap();
+
+        assert_eq!(6, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(7, m.cpu_peek(0xC000).unwrap());
+        assert_eq!(1, m.ppu_peek(0x0000).unwrap());
+    }
+
+    #[test]
+    fn bus_conflict_ands_with_rom()
+    {
+        // Bank 5 written over a ROM byte of 3 ends up as bank 1.
+        let mut m = setup(Board::Uxrom, 8, 0, true);
+        m.cpu_write(0xC000, 3).unwrap();
+        m.cpu_write(0x8000, 5).unwrap();
+
+        assert_eq!(1, m.cpu_peek(0x8000).unwrap());
+    }
+
+    #[test]
+    fn no_bus_conflict_when_disabled()
+    {
+        let mut m = setup(Board::Uxrom, 8, 0, false);
+        m.cpu_write(0x8000, 3).unwrap();
+        m.cpu_write(0x8000, 5).unwrap();
+
+        assert_eq!(5, m.cpu_peek(0x8000).unwrap());
+    }
+}
@@ -0,0 +1,331 @@
+use crate::memory::*;
+use crate::cartridge::{Cartridge, CartridgeError, Mirroring};
+use crate::mapper::{Chr, Mapper};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+const PRG_RAM_ENABLE: u8 = 0b10000000;
+const PRG_RAM_SELECT: u8 = 0b01000000;
+
+const IRQ_ENABLE: u8     = 0b00000001;
+const COUNTER_ENABLE: u8 = 0b10000000;
+
+// Mapper 69: Sunsoft FME-7 and 5A/5B. Registers are written indirectly,
+//   $8000-$9FFF command, $A000-$BFFF parameter for it
+//   $C000-$DFFF audio register select, $E000-$FFFF audio register data
+// Commands:
+//   $0-$7 1 KiB CHR banks
+//   $8    $6000-$7FFF: ROM bank, or RAM if bit 6 is set (enabled by bit 7)
+//   $9-$B 8 KiB PRG banks at $8000, $A000, $C000; $E000 is fixed to the last
+//   $C    mirroring
+//   $D    IRQ control, writing it acknowledges the IRQ
+//   $E/$F IRQ counter low/high byte
+// The 5B's audio registers are stored but not played.
+pub struct Fme7
+{
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Chr,
+    command: u8,
+    chr_banks: [u8; 8],
+    // $6000, $8000, $A000, $C000
+    prg_banks: [u8; 4],
+    mirroring: Mirroring,
+    irq_control: u8,
+    irq_counter: u16,
+    irq_pending: bool,
+    audio_select: u8,
+    audio: [u8; 16]
+}
+
+impl Fme7
+{
+    // Only $E000-$FFFF is not switchable, it shows the last PRG bank.
+    pub fn new(cart: Cartridge, state: PowerOnState) -> Result<Self, CartridgeError>
+    {
+        if cart.prg_rom.len() < PRG_BANK_SIZE
+        {
+            return Err(CartridgeError::TooSmall { section: "PRG ROM", minimum: PRG_BANK_SIZE, found: cart.prg_rom.len() });
+        }
+
+        let mut prg_ram = vec![0; cart.prg_ram_size + cart.prg_nvram_size];
+        state.fill(&mut prg_ram);
+
+        Ok(Fme7 {
+            prg_rom: cart.prg_rom,
+            prg_ram,
+            chr: Chr::new(cart.chr_rom, cart.chr_ram_size, state),
+            command: 0,
+            chr_banks: [0; 8],
+            prg_banks: [0; 4],
+            mirroring: cart.mirroring,
+            irq_control: 0,
+            irq_counter: 0,
+            irq_pending: false,
+            audio_select: 0,
+            audio: [0; 16]
+        })
+    }
+
+    fn prg_ram_selected(&self) -> bool
+    {
+        self.prg_banks[0] & PRG_RAM_SELECT != 0
+    }
+
+    fn prg_ram_enabled(&self) -> bool
+    {
+        self.prg_ram_selected() && self.prg_banks[0] & PRG_RAM_ENABLE != 0 && !self.prg_ram.is_empty()
+    }
+
+    fn prg_offset(&self, address: usize) -> usize
+    {
+        let offset = match address
+        {
+            0xE000..=0xFFFF => self.prg_rom.len() - PRG_BANK_SIZE + (address & 0x1FFF),
+            _ => {
+                let bank = self.prg_banks[(address - 0x6000) / PRG_BANK_SIZE] & 0x3F;
+                bank as usize * PRG_BANK_SIZE + (address & 0x1FFF)
+            }
+        };
+        offset % self.prg_rom.len()
+    }
+
+    fn chr_offset(&self, address: usize) -> usize
+    {
+        self.chr_banks[address / CHR_BANK_SIZE] as usize * CHR_BANK_SIZE + (address & 0x03FF)
+    }
+
+    fn write_parameter(&mut self, data: u8)
+    {
+        match self.command
+        {
+            0x0..=0x7 => self.chr_banks[self.command as usize] = data,
+            0x8..=0xB => self.prg_banks[self.command as usize - 0x8] = data,
+            0xC => {
+                self.mirroring = match data & 0x03
+                {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenA,
+                    _ => Mirroring::SingleScreenB
+                };
+            },
+            0xD => {
+                self.irq_control = data;
+                self.irq_pending = false;
+            },
+            0xE => self.irq_counter = (self.irq_counter & 0xFF00) | data as u16,
+            _ => self.irq_counter = (self.irq_counter & 0x00FF) | ((data as u16) << 8)
+        }
+    }
+}
+
+impl Mapper for Fme7
+{
+    fn cpu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        match address
+        {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(address - 0x6000) % len] = data;
+            },
+            0x8000..=0x9FFF => self.command = data & 0x0F,
+            0xA000..=0xBFFF => self.write_parameter(data),
+            0xC000..=0xDFFF => self.audio_select = data & 0x0F,
+            0xE000..=0xFFFF => self.audio[self.audio_select as usize] = data,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn cpu_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        match address
+        {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => Ok(self.prg_ram[(address - 0x6000) % self.prg_ram.len()]),
+            0x6000..=0x7FFF if self.prg_ram_selected() => Ok(0x00),
+            0x6000..=0xFFFF => Ok(self.prg_rom[self.prg_offset(address)]),
+            _ => Ok(0x00)
+        }
+    }
+
+    fn cpu_open_bus_mask(&self, address: usize) -> u8
+    {
+        match address
+        {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => 0x00,
+            0x6000..=0x7FFF if self.prg_ram_selected() => 0xFF,
+            0x6000..=0xFFFF => 0x00,
+            _ => 0xFF
+        }
+    }
+
+    fn ppu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        let offset = self.chr_offset(address);
+        self.chr.write(offset, data);
+        Ok(())
+    }
+
+    fn ppu_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        Ok(self.chr.read(self.chr_offset(address)))
+    }
+
+    fn mirroring(&self) -> Mirroring
+    {
+        self.mirroring
+    }
+
+    // The counter decrements every CPU cycle and fires when it wraps from
+    // $0000 to $FFFF.
+    fn notify_cpu_cycles(&mut self, cycles: u32)
+    {
+        if self.irq_control & COUNTER_ENABLE == 0
+        {
+            return;
+        }
+        if cycles > self.irq_counter as u32 && self.irq_control & IRQ_ENABLE != 0
+        {
+            self.irq_pending = true;
+        }
+        self.irq_counter = (self.irq_counter as u32).wrapping_sub(cycles) as u16;
+    }
+
+    fn irq(&self) -> bool
+    {
+        self.irq_pending
+    }
+
+    fn prg_ram(&mut self) -> &mut [u8]
+    {
+        &mut self.prg_ram
+    }
+
+    fn name(&self) -> &'static str
+    {
+        "FME-7"
+    }
+}
+
+#[cfg(test)]
+mod fme7tests
+{
+    use crate::mapper::fme7::*;
+    use crate::mapper::test_rom;
+
+    fn cartridge() -> Cartridge
+    {
+        test_rom(69, PRG_BANK_SIZE, 16, CHR_BANK_SIZE, 32)
+    }
+
+    fn setup() -> Fme7
+    {
+        Fme7::new(cartridge(), PowerOnState::Zeros).unwrap()
+    }
+
+    #[test]
+    fn rejects_prg_rom_smaller_than_fixed_bank()
+    {
+        let mut cart = cartridge();
+        cart.prg_rom.truncate(0x1000);
+
+        match Fme7::new(cart, PowerOnState::Zeros)
+        {
+            Err(e) => assert_eq!(CartridgeError::TooSmall { section: "PRG ROM", minimum: 0x2000, found: 0x1000 }, e),
+            Ok(_) => panic!("FME-7 with 4 KiB PRG ROM was accepted")
+        }
+    }
+
+    fn command(m: &mut Fme7, command: u8, data: u8)
+    {
+        m.cpu_write(0x8000, command).unwrap();
+        m.cpu_write(0xA000, data).unwrap();
+    }
+
+    #[test]
+    fn prg_and_chr_banks()
+    {
+        let mut m = setup();
+        command(&mut m, 0x9, 3);
+        command(&mut m, 0xA, 4);
+        command(&mut m, 0xB, 5);
+        command(&mut m, 0x5, 17);
+
+        assert_eq!(3, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(4, m.cpu_peek(0xA000).unwrap());
+        assert_eq!(5, m.cpu_peek(0xC000).unwrap());
+        assert_eq!(15, m.cpu_peek(0xE000).unwrap());
+        assert_eq!(17, m.ppu_peek(0x1400).unwrap());
+    }
+
+    #[test]
+    fn bank_at_6000_is_rom_or_ram()
+    {
+        let mut m = setup();
+        command(&mut m, 0x8, 7);
+        assert_eq!(7, m.cpu_peek(0x6000).unwrap());
+
+        command(&mut m, 0x8, PRG_RAM_SELECT);
+        m.cpu_write(0x6000, 0x12).unwrap();
+        assert_eq!(0xFF, m.cpu_open_bus_mask(0x6000));
+
+        command(&mut m, 0x8, PRG_RAM_SELECT | PRG_RAM_ENABLE);
+        m.cpu_write(0x6000, 0x12).unwrap();
+        assert_eq!(0x12, m.cpu_peek(0x6000).unwrap());
+    }
+
+    #[test]
+    fn mirroring_command()
+    {
+        let mut m = setup();
+        command(&mut m, 0xC, 1);
+        assert_eq!(Mirroring::Horizontal, m.mirroring());
+        command(&mut m, 0xC, 3);
+        assert_eq!(Mirroring::SingleScreenB, m.mirroring());
+    }
+
+    #[test]
+    fn irq_fires_when_counter_wraps()
+    {
+        let mut m = setup();
+        command(&mut m, 0xE, 0x00);
+        command(&mut m, 0xF, 0x01);
+        command(&mut m, 0xD, COUNTER_ENABLE | IRQ_ENABLE);
+
+        m.notify_cpu_cycles(0x100);
+        assert!(!m.irq());
+        m.notify_cpu_cycles(1);
+        assert!(m.irq());
+        assert_eq!(0xFFFF, m.irq_counter);
+
+        command(&mut m, 0xD, COUNTER_ENABLE);
+        assert!(!m.irq());
+        m.notify_cpu_cycles(0x10000);
+        assert!(!m.irq());
+    }
+
+    #[test]
+    fn counter_stops_when_disabled()
+    {
+        let mut m = setup();
+        command(&mut m, 0xE, 0x10);
+        command(&mut m, 0xD, IRQ_ENABLE);
+
+        m.notify_cpu_cycles(0x20);
+        assert!(!m.irq());
+        assert_eq!(0x10, m.irq_counter);
+    }
+
+    #[test]
+    fn audio_registers_are_latched()
+    {
+        let mut m = setup();
+        m.cpu_write(0xC000, 0x07).unwrap();
+        m.cpu_write(0xE000, 0x38).unwrap();
+
+        assert_eq!(0x38, m.audio[7]);
+    }
+}
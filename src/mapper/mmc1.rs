@@ -0,0 +1,354 @@
+use crate::memory::*;
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::mapper::{Chr, Mapper};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x1000;
+
+// The shift register starts out holding just this marker bit. Once it has
+// been shifted down to bit 0 the fifth write is in.
+const SHIFT_EMPTY: u8 = 0b10000;
+const SHIFT_RESET: u8 = 0b10000000;
+
+const CONTROL_MIRRORING: u8 = 0b00011;
+const CONTROL_PRG_MODE: u8  = 0b01100;
+const CONTROL_CHR_4K: u8    = 0b10000;
+const PRG_RAM_DISABLE: u8   = 0b10000;
+
+// SUROM and friends use bit 4 of the CHR bank registers to select the
+// 256 KiB half of a 512 KiB PRG ROM.
+const OUTER_PRG_SIZE: usize = 0x40000;
+
+// Mapper 1: MMC1. The CPU loads the internal registers one bit at a time
+// through a serial port at $8000-$FFFF, address bits 13 and 14 of the
+// fifth write select the register:
+//   $8000 control: CPPMM, C = CHR mode, PP = PRG mode, MM = mirroring
+//   $A000 CHR bank 0
+//   $C000 CHR bank 1, only used in 4 KiB CHR mode
+//   $E000 PRG bank: RPPPP, R = PRG RAM disable
+pub struct Mmc1
+{
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Chr,
+    shift: u8,
+    control: u8,
+    chr_bank0: u8,
+    chr_bank1: u8,
+    prg_bank: u8
+}
+
+impl Mmc1
+{
+    pub fn new(cart: Cartridge, state: PowerOnState) -> Self
+    {
+        let mut prg_ram = vec![0; cart.prg_ram_size + cart.prg_nvram_size];
+        state.fill(&mut prg_ram);
+
+        Mmc1 {
+            prg_rom: cart.prg_rom,
+            prg_ram,
+            chr: Chr::new(cart.chr_rom, cart.chr_ram_size, state),
+            shift: SHIFT_EMPTY,
+            // Most boards power up with the last bank fixed at $C000.
+            control: CONTROL_PRG_MODE,
+            chr_bank0: 0,
+            chr_bank1: 0,
+            prg_bank: 0
+        }
+    }
+
+    fn write_register(&mut self, address: usize, data: u8)
+    {
+        if data & SHIFT_RESET != 0
+        {
+            self.shift = SHIFT_EMPTY;
+            self.control |= CONTROL_PRG_MODE;
+            return;
+        }
+
+        let done = self.shift & 1 != 0;
+        self.shift = (self.shift >> 1) | ((data & 1) << 4);
+        if !done
+        {
+            return;
+        }
+
+        let value = self.shift;
+        self.shift = SHIFT_EMPTY;
+        match address & 0xE000
+        {
+            0x8000 => self.control = value,
+            0xA000 => self.chr_bank0 = value,
+            0xC000 => self.chr_bank1 = value,
+            _ => self.prg_bank = value
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool
+    {
+        !self.prg_ram.is_empty() && self.prg_bank & PRG_RAM_DISABLE == 0
+    }
+
+    // Offset into PRG ROM for a CPU address in $8000-$FFFF.
+    fn prg_offset(&self, address: usize) -> usize
+    {
+        let bank = (self.prg_bank & 0x0F) as usize;
+        let last = (self.prg_rom.len().min(OUTER_PRG_SIZE) / PRG_BANK_SIZE).saturating_sub(1);
+        let upper = address >= 0xC000;
+        let bank = match (self.control & CONTROL_PRG_MODE) >> 2
+        {
+            0 | 1 => (bank & !1) | upper as usize,
+            2 => if upper { bank } else { 0 },
+            _ => if upper { last } else { bank }
+        };
+
+        let outer = if self.prg_rom.len() > OUTER_PRG_SIZE { (self.chr_bank0 & 0x10) as usize >> 4 } else { 0 };
+        (outer * OUTER_PRG_SIZE + bank * PRG_BANK_SIZE + (address & 0x3FFF)) % self.prg_rom.len()
+    }
+
+    // Offset into CHR ROM/RAM for a PPU address in $0000-$1FFF.
+    fn chr_offset(&self, address: usize) -> usize
+    {
+        let bank = if self.control & CONTROL_CHR_4K != 0
+        {
+            if address < 0x1000 { self.chr_bank0 } else { self.chr_bank1 }
+        }
+        else
+        {
+            (self.chr_bank0 & !1) | (address >= 0x1000) as u8
+        };
+        bank as usize * CHR_BANK_SIZE + (address & 0x0FFF)
+    }
+}
+
+impl Mapper for Mmc1
+{
+    fn cpu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        match address
+        {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(address - 0x6000) % len] = data;
+            },
+            0x8000..=0xFFFF => self.write_register(address, data),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn cpu_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        match address
+        {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => Ok(self.prg_ram[(address - 0x6000) % self.prg_ram.len()]),
+            0x8000..=0xFFFF => Ok(self.prg_rom[self.prg_offset(address)]),
+            _ => Ok(0x00)
+        }
+    }
+
+    fn cpu_open_bus_mask(&self, address: usize) -> u8
+    {
+        match address
+        {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => 0x00,
+            0x8000..=0xFFFF => 0x00,
+            _ => 0xFF
+        }
+    }
+
+    fn ppu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        let offset = self.chr_offset(address);
+        self.chr.write(offset, data);
+        Ok(())
+    }
+
+    fn ppu_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        Ok(self.chr.read(self.chr_offset(address)))
+    }
+
+    fn mirroring(&self) -> Mirroring
+    {
+        match self.control & CONTROL_MIRRORING
+        {
+            0 => Mirroring::SingleScreenA,
+            1 => Mirroring::SingleScreenB,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal
+        }
+    }
+
+    fn prg_ram(&mut self) -> &mut [u8]
+    {
+        &mut self.prg_ram
+    }
+
+    fn name(&self) -> &'static str
+    {
+        "MMC1"
+    }
+}
+
+#[cfg(test)]
+mod mmc1tests
+{
+    use crate::mapper::mmc1::*;
+    use crate::mapper::test_rom;
+
+    // chr_banks counts 8 KiB like the header does.
+    fn setup(prg_banks: usize, chr_banks: usize) -> Mmc1
+    {
+        Mmc1::new(test_rom(1, PRG_BANK_SIZE, prg_banks, CHR_BANK_SIZE, chr_banks * 2), PowerOnState::Zeros)
+    }
+
+    // Five writes, LSB first, like the games do it.
+    fn load(m: &mut Mmc1, address: usize, value: u8)
+    {
+        for i in 0..5
+        {
+            m.cpu_write(address, (value >> i) & 1).unwrap();
+        }
+    }
+
+    #[test]
+    fn powers_up_with_last_bank_fixed()
+    {
+        let m = setup(8, 1);
+
+        assert_eq!(0, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(7, m.cpu_peek(0xC000).unwrap());
+        assert_eq!(7, m.cpu_peek(0xFFFF).unwrap());
+    }
+
+    #[test]
+    fn register_is_written_on_fifth_write_only()
+    {
+        let mut m = setup(8, 1);
+        for bit in &[1, 0, 1, 0]
+        {
+            m.cpu_write(0xE000, *bit).unwrap();
+        }
+        assert_eq!(0, m.cpu_peek(0x8000).unwrap());
+
+        m.cpu_write(0xE000, 0).unwrap();
+        assert_eq!(5, m.cpu_peek(0x8000).unwrap());
+    }
+
+    #[test]
+    fn switches_bank_at_8000_in_mode_3()
+    {
+        let mut m = setup(8, 1);
+        load(&mut m, 0xE000, 5);
+
+        assert_eq!(5, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(7, m.cpu_peek(0xC000).unwrap());
+    }
+
+    #[test]
+    fn fixes_first_bank_in_mode_2()
+    {
+        let mut m = setup(8, 1);
+        load(&mut m, 0x8000, 0b01000);
+        load(&mut m, 0xE000, 3);
+
+        assert_eq!(0, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(3, m.cpu_peek(0xC000).unwrap());
+    }
+
+    #[test]
+    fn switches_32k_ignoring_low_bit()
+    {
+        let mut m = setup(8, 1);
+        load(&mut m, 0x8000, 0b00000);
+        load(&mut m, 0xE000, 5);
+
+        assert_eq!(4, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(5, m.cpu_peek(0xC000).unwrap());
+    }
+
+    #[test]
+    fn reset_bit_clears_shift_register_and_sets_mode_3()
+    {
+        let mut m = setup(8, 1);
+        load(&mut m, 0x8000, 0b01000);
+        m.cpu_write(0xE000, 1).unwrap();
+        m.cpu_write(0xE000, 1).unwrap();
+        m.cpu_write(0x8000, 0x80).unwrap();
+        load(&mut m, 0xE000, 2);
+
+        assert_eq!(2, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(7, m.cpu_peek(0xC000).unwrap());
+    }
+
+    #[test]
+    fn chr_8k_mode_ignores_low_bit()
+    {
+        let mut m = setup(2, 2);
+        load(&mut m, 0xA000, 3);
+
+        assert_eq!(2, m.ppu_peek(0x0000).unwrap());
+        assert_eq!(3, m.ppu_peek(0x1000).unwrap());
+    }
+
+    #[test]
+    fn chr_4k_mode_switches_both_halves()
+    {
+        let mut m = setup(2, 2);
+        load(&mut m, 0x8000, 0b11100);
+        load(&mut m, 0xA000, 3);
+        load(&mut m, 0xC000, 1);
+
+        assert_eq!(3, m.ppu_peek(0x0000).unwrap());
+        assert_eq!(1, m.ppu_peek(0x1FFF).unwrap());
+    }
+
+    #[test]
+    fn control_selects_mirroring()
+    {
+        let mut m = setup(2, 1);
+        let modes = [Mirroring::SingleScreenA, Mirroring::SingleScreenB, Mirroring::Vertical, Mirroring::Horizontal];
+        for (i, mode) in modes.iter().enumerate()
+        {
+            load(&mut m, 0x8000, 0b01100 | i as u8);
+            assert_eq!(*mode, m.mirroring());
+        }
+    }
+
+    #[test]
+    fn prg_ram_can_be_disabled()
+    {
+        let mut m = setup(2, 1);
+        m.cpu_write(0x6000, 0x42).unwrap();
+        assert_eq!(0x42, m.cpu_peek(0x6000).unwrap());
+
+        load(&mut m, 0xE000, PRG_RAM_DISABLE);
+        m.cpu_write(0x6000, 0x24).unwrap();
+        assert_eq!(0xFF, m.cpu_open_bus_mask(0x6000));
+
+        load(&mut m, 0xE000, 0);
+        assert_eq!(0x42, m.cpu_peek(0x6000).unwrap());
+    }
+
+    #[test]
+    fn surom_selects_256k_half_with_chr_bank_bit_4()
+    {
+        let mut m = setup(32, 0);
+        assert_eq!(15, m.cpu_peek(0xC000).unwrap());
+
+        load(&mut m, 0xA000, 0x10);
+        assert_eq!(31, m.cpu_peek(0xC000).unwrap());
+        assert_eq!(16, m.cpu_peek(0x8000).unwrap());
+    }
+
+    #[test]
+    fn chr_ram_is_writable()
+    {
+        let mut m = setup(2, 0);
+        m.ppu_write(0x1234, 0x99).unwrap();
+
+        assert_eq!(0x99, m.ppu_peek(0x1234).unwrap());
+    }
+}
@@ -0,0 +1,259 @@
+use crate::memory::*;
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::mapper::{Chr, Mapper};
+
+const CHR_BANK_SIZE: usize = 0x1000;
+
+const LATCH_FD: u8 = 0xFD;
+const LATCH_FE: u8 = 0xFE;
+
+// Mappers 9 (MMC2, Punch-Out!!) and 10 (MMC4, Fire Emblem). Each 4 KiB
+// half of the pattern tables has two CHR bank registers and a latch that
+// picks one of them. The latch flips when the PPU fetches tile $FD or $FE
+// from that half, so a game can switch banks in the middle of a line just
+// by placing these tiles.
+//   $A000 PRG bank: 8 KiB at $8000 (MMC2), 16 KiB at $8000 (MMC4)
+//   $B000 CHR bank for $0000 with latch $FD, $C000 with latch $FE
+//   $D000 CHR bank for $1000 with latch $FD, $E000 with latch $FE
+//   $F000 mirroring
+pub struct Mmc2
+{
+    mmc4: bool,
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Chr,
+    prg_bank: u8,
+    // [half][latch $FD, latch $FE]
+    chr_banks: [[u8; 2]; 2],
+    latches: [u8; 2],
+    mirroring: Mirroring
+}
+
+impl Mmc2
+{
+    pub fn new(cart: Cartridge, state: PowerOnState) -> Self
+    {
+        let mmc4 = cart.mapper == 10;
+        // MMC2 boards have no PRG RAM, even though an iNES 1.0 header cannot
+        // say so.
+        let ram_size = if mmc4 { cart.prg_ram_size + cart.prg_nvram_size } else { 0 };
+        let mut prg_ram = vec![0; ram_size];
+        state.fill(&mut prg_ram);
+
+        Mmc2 {
+            mmc4,
+            prg_rom: cart.prg_rom,
+            prg_ram,
+            chr: Chr::new(cart.chr_rom, cart.chr_ram_size, state),
+            prg_bank: 0,
+            chr_banks: [[0, 0], [0, 0]],
+            latches: [LATCH_FE, LATCH_FE],
+            mirroring: cart.mirroring
+        }
+    }
+
+    fn prg_offset(&self, address: usize) -> usize
+    {
+        // MMC2 switches 8 KiB and fixes the last three banks, MMC4 switches
+        // 16 KiB and fixes the last one.
+        let bank_size = if self.mmc4 { 0x4000 } else { 0x2000 };
+        let banks = self.prg_rom.len() / bank_size;
+        let slots = 0x8000 / bank_size;
+        let slot = (address - 0x8000) / bank_size;
+        let bank = if slot == 0 { self.prg_bank as usize } else { (banks + slot).saturating_sub(slots) };
+        (bank * bank_size + (address & (bank_size - 1))) % self.prg_rom.len()
+    }
+
+    fn chr_offset(&self, address: usize) -> usize
+    {
+        let half = address / CHR_BANK_SIZE;
+        let which = (self.latches[half] == LATCH_FE) as usize;
+        self.chr_banks[half][which] as usize * CHR_BANK_SIZE + (address & 0x0FFF)
+    }
+}
+
+impl Mapper for Mmc2
+{
+    fn cpu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        match address
+        {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(address - 0x6000) % len] = data;
+            },
+            0xA000..=0xAFFF => self.prg_bank = data & 0x0F,
+            0xB000..=0xBFFF => self.chr_banks[0][0] = data & 0x1F,
+            0xC000..=0xCFFF => self.chr_banks[0][1] = data & 0x1F,
+            0xD000..=0xDFFF => self.chr_banks[1][0] = data & 0x1F,
+            0xE000..=0xEFFF => self.chr_banks[1][1] = data & 0x1F,
+            0xF000..=0xFFFF => {
+                self.mirroring = if data & 1 == 0 { Mirroring::Vertical } else { Mirroring::Horizontal };
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn cpu_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        match address
+        {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => Ok(self.prg_ram[(address - 0x6000) % self.prg_ram.len()]),
+            0x8000..=0xFFFF => Ok(self.prg_rom[self.prg_offset(address)]),
+            _ => Ok(0x00)
+        }
+    }
+
+    fn cpu_open_bus_mask(&self, address: usize) -> u8
+    {
+        match address
+        {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => 0x00,
+            0x8000..=0xFFFF => 0x00,
+            _ => 0xFF
+        }
+    }
+
+    fn ppu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        let offset = self.chr_offset(address);
+        self.chr.write(offset, data);
+        Ok(())
+    }
+
+    fn ppu_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        Ok(self.chr.read(self.chr_offset(address)))
+    }
+
+    fn mirroring(&self) -> Mirroring
+    {
+        self.mirroring
+    }
+
+    // The latch changes after the fetch, so the triggering tile itself
+    // still comes from the old bank. MMC2 only reacts to the exact address
+    // of the last byte fetched for tile $FD/$FE in the lower half, MMC4 to
+    // the whole tile row range like both chips do in the upper half.
+    fn notify_pattern_fetch(&mut self, address: usize)
+    {
+        let half = address / CHR_BANK_SIZE;
+        let exact = half == 0 && !self.mmc4;
+        let latch = match address & 0x0FFF
+        {
+            0x0FD8 => LATCH_FD,
+            0x0FE8 => LATCH_FE,
+            0x0FD9..=0x0FDF if !exact => LATCH_FD,
+            0x0FE9..=0x0FEF if !exact => LATCH_FE,
+            _ => return
+        };
+        self.latches[half] = latch;
+    }
+
+    fn prg_ram(&mut self) -> &mut [u8]
+    {
+        &mut self.prg_ram
+    }
+
+    fn name(&self) -> &'static str
+    {
+        if self.mmc4 { "MMC4" } else { "MMC2" }
+    }
+}
+
+#[cfg(test)]
+mod mmc2tests
+{
+    use crate::mapper::mmc2::*;
+    use crate::mapper::test_rom;
+
+    fn setup(mapper: u8) -> Mmc2
+    {
+        Mmc2::new(test_rom(mapper, 0x2000, 16, CHR_BANK_SIZE, 16), PowerOnState::Zeros)
+    }
+
+    #[test]
+    fn mmc2_switches_8k_and_fixes_last_three()
+    {
+        let mut m = setup(9);
+        m.cpu_write(0xA000, 4).unwrap();
+
+        assert_eq!(4, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(13, m.cpu_peek(0xA000).unwrap());
+        assert_eq!(14, m.cpu_peek(0xC000).unwrap());
+        assert_eq!(15, m.cpu_peek(0xE000).unwrap());
+    }
+
+    #[test]
+    fn mmc4_switches_16k_and_fixes_last()
+    {
+        let mut m = setup(10);
+        m.cpu_write(0xA000, 2).unwrap();
+
+        assert_eq!(4, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(5, m.cpu_peek(0xA000).unwrap());
+        assert_eq!(14, m.cpu_peek(0xC000).unwrap());
+        assert_eq!(15, m.cpu_peek(0xE000).unwrap());
+    }
+
+    #[test]
+    fn latch_selects_chr_bank()
+    {
+        let mut m = setup(9);
+        m.cpu_write(0xB000, 1).unwrap();
+        m.cpu_write(0xC000, 2).unwrap();
+        m.cpu_write(0xD000, 3).unwrap();
+        m.cpu_write(0xE000, 4).unwrap();
+
+        assert_eq!(2, m.ppu_peek(0x0000).unwrap());
+        assert_eq!(4, m.ppu_peek(0x1000).unwrap());
+
+        m.notify_pattern_fetch(0x0FD8);
+        m.notify_pattern_fetch(0x1FDA);
+        assert_eq!(1, m.ppu_peek(0x0000).unwrap());
+        assert_eq!(3, m.ppu_peek(0x1000).unwrap());
+
+        m.notify_pattern_fetch(0x0FE8);
+        assert_eq!(2, m.ppu_peek(0x0000).unwrap());
+    }
+
+    #[test]
+    fn mmc2_lower_latch_needs_exact_address()
+    {
+        let mut m = setup(9);
+        m.cpu_write(0xB000, 1).unwrap();
+        m.cpu_write(0xC000, 2).unwrap();
+
+        m.notify_pattern_fetch(0x0FDA);
+        assert_eq!(2, m.ppu_peek(0x0000).unwrap());
+
+        let mut m = setup(10);
+        m.cpu_write(0xB000, 1).unwrap();
+        m.notify_pattern_fetch(0x0FDA);
+        assert_eq!(1, m.ppu_peek(0x0000).unwrap());
+    }
+
+    #[test]
+    fn ppu_fetch_flips_latch()
+    {
+        use crate::ppu::ppu;
+        use crate::log;
+        use std::{cell::RefCell, rc::Rc, sync::{Arc, Mutex}};
+
+        let mapper = Rc::new(RefCell::new(setup(9)));
+        mapper.borrow_mut().cpu_write(0xB000, 1).unwrap();
+        mapper.borrow_mut().cpu_write(0xC000, 2).unwrap();
+        let mut p = ppu::new(Arc::new(Mutex::new(log::logger::new())), Rc::new(RefCell::new(vec![0u32; 320 * 240])));
+        p.set_mapper(mapper.clone());
+
+        // Tile $FD in the first row of the nametable, background from $0000.
+        for (address, data) in &[(0x06, 0x00), (0x06, 0x20), (0x07, 0xFD), (0x01, 0x08)]
+        {
+            p.write_byte(*address, *data).unwrap();
+        }
+        p.tick(86);
+
+        assert_eq!(1, mapper.borrow().ppu_peek(0x0000).unwrap());
+    }
+}
@@ -0,0 +1,373 @@
+use crate::memory::*;
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::mapper::{Chr, Mapper};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+const SELECT_REGISTER: u8  = 0b00000111;
+const SELECT_PRG_MODE: u8  = 0b01000000;
+const SELECT_CHR_A12: u8   = 0b10000000;
+
+const PRG_RAM_ENABLE: u8   = 0b10000000;
+const PRG_RAM_PROTECT: u8  = 0b01000000;
+
+// Mapper 4: MMC3. Registers are decoded by A0 and A13-A14:
+//   $8000 bank select, $8001 bank data
+//   $A000 mirroring,   $A001 PRG RAM protect
+//   $C000 IRQ latch,   $C001 IRQ reload
+//   $E000 IRQ disable, $E001 IRQ enable
+//
+// The IRQ counter is clocked by rising edges of PPU A12, i.e. normally
+// once per scanline, which games use for status bars and raster effects.
+pub struct Mmc3
+{
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Chr,
+    bank_select: u8,
+    banks: [u8; 8],
+    mirroring: Mirroring,
+    four_screen: bool,
+    prg_ram_control: u8,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool
+}
+
+impl Mmc3
+{
+    pub fn new(cart: Cartridge, state: PowerOnState) -> Self
+    {
+        let mut prg_ram = vec![0; cart.prg_ram_size + cart.prg_nvram_size];
+        state.fill(&mut prg_ram);
+
+        Mmc3 {
+            prg_rom: cart.prg_rom,
+            prg_ram,
+            chr: Chr::new(cart.chr_rom, cart.chr_ram_size, state),
+            bank_select: 0,
+            banks: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring: cart.mirroring,
+            // Four screen boards have their own VRAM and ignore $A000.
+            four_screen: cart.mirroring == Mirroring::FourScreen,
+            prg_ram_control: PRG_RAM_ENABLE,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false
+        }
+    }
+
+    fn prg_bank_count(&self) -> usize
+    {
+        self.prg_rom.len() / PRG_BANK_SIZE
+    }
+
+    // Offset into PRG ROM for a CPU address in $8000-$FFFF. R6 and the
+    // second to last bank swap places with the PRG mode bit.
+    fn prg_offset(&self, address: usize) -> usize
+    {
+        let second_last = self.prg_bank_count().saturating_sub(2);
+        let swapped = self.bank_select & SELECT_PRG_MODE != 0;
+        let bank = match (address - 0x8000) / PRG_BANK_SIZE
+        {
+            0 => if swapped { second_last } else { self.banks[6] as usize },
+            1 => self.banks[7] as usize,
+            2 => if swapped { self.banks[6] as usize } else { second_last },
+            _ => self.prg_bank_count().saturating_sub(1)
+        };
+        (bank * PRG_BANK_SIZE + (address & 0x1FFF)) % self.prg_rom.len()
+    }
+
+    // Offset into CHR for a PPU address in $0000-$1FFF. R0 and R1 select
+    // 2 KiB banks, R2-R5 1 KiB banks, the A12 bit swaps both halves.
+    fn chr_offset(&self, address: usize) -> usize
+    {
+        let address = if self.bank_select & SELECT_CHR_A12 != 0 { address ^ 0x1000 } else { address };
+        let slot = address / CHR_BANK_SIZE;
+        let bank = match slot
+        {
+            0 | 1 => (self.banks[0] & !1) as usize | slot,
+            2 | 3 => (self.banks[1] & !1) as usize | (slot - 2),
+            _ => self.banks[slot - 2] as usize
+        };
+        bank * CHR_BANK_SIZE + (address & 0x03FF)
+    }
+
+    fn prg_ram_readable(&self) -> bool
+    {
+        !self.prg_ram.is_empty() && self.prg_ram_control & PRG_RAM_ENABLE != 0
+    }
+
+    fn prg_ram_writable(&self) -> bool
+    {
+        self.prg_ram_readable() && self.prg_ram_control & PRG_RAM_PROTECT == 0
+    }
+
+    fn write_register(&mut self, address: usize, data: u8)
+    {
+        let odd = address & 1 != 0;
+        match (address & 0xE000, odd)
+        {
+            (0x8000, false) => self.bank_select = data,
+            (0x8000, true) => self.banks[(self.bank_select & SELECT_REGISTER) as usize] = data,
+            (0xA000, false) => {
+                if !self.four_screen
+                {
+                    self.mirroring = if data & 1 == 0 { Mirroring::Vertical } else { Mirroring::Horizontal };
+                }
+            },
+            (0xA000, true) => self.prg_ram_control = data,
+            (0xC000, false) => self.irq_latch = data,
+            (0xC000, true) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            },
+            (_, false) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            },
+            (_, true) => self.irq_enabled = true
+        }
+    }
+}
+
+impl Mapper for Mmc3
+{
+    fn cpu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        match address
+        {
+            0x6000..=0x7FFF if self.prg_ram_writable() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(address - 0x6000) % len] = data;
+            },
+            0x8000..=0xFFFF => self.write_register(address, data),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn cpu_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        match address
+        {
+            0x6000..=0x7FFF if self.prg_ram_readable() => Ok(self.prg_ram[(address - 0x6000) % self.prg_ram.len()]),
+            0x8000..=0xFFFF => Ok(self.prg_rom[self.prg_offset(address)]),
+            _ => Ok(0x00)
+        }
+    }
+
+    fn cpu_open_bus_mask(&self, address: usize) -> u8
+    {
+        match address
+        {
+            0x6000..=0x7FFF if self.prg_ram_readable() => 0x00,
+            0x8000..=0xFFFF => 0x00,
+            _ => 0xFF
+        }
+    }
+
+    fn ppu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        let offset = self.chr_offset(address);
+        self.chr.write(offset, data);
+        Ok(())
+    }
+
+    fn ppu_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        Ok(self.chr.read(self.chr_offset(address)))
+    }
+
+    fn mirroring(&self) -> Mirroring
+    {
+        self.mirroring
+    }
+
+    fn notify_a12_rise(&mut self)
+    {
+        if self.irq_counter == 0 || self.irq_reload
+        {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        }
+        else
+        {
+            self.irq_counter -= 1;
+        }
+
+        if self.irq_counter == 0 && self.irq_enabled
+        {
+            self.irq_pending = true;
+        }
+    }
+
+    fn irq(&self) -> bool
+    {
+        self.irq_pending
+    }
+
+    fn prg_ram(&mut self) -> &mut [u8]
+    {
+        &mut self.prg_ram
+    }
+
+    fn name(&self) -> &'static str
+    {
+        "MMC3"
+    }
+}
+
+#[cfg(test)]
+mod mmc3tests
+{
+    use crate::mapper::mmc3::*;
+    use crate::mapper::test_rom;
+
+    fn setup(prg_16k: usize, chr_8k: usize) -> Mmc3
+    {
+        Mmc3::new(test_rom(4, PRG_BANK_SIZE, prg_16k * 2, CHR_BANK_SIZE, chr_8k * 8), PowerOnState::Zeros)
+    }
+
+    fn set_bank(m: &mut Mmc3, select: u8, bank: u8)
+    {
+        m.cpu_write(0x8000, select).unwrap();
+        m.cpu_write(0x8001, bank).unwrap();
+    }
+
+    #[test]
+    fn last_bank_is_fixed_at_e000()
+    {
+        let m = setup(8, 1);
+
+        assert_eq!(15, m.cpu_peek(0xE000).unwrap());
+        assert_eq!(15, m.cpu_peek(0xFFFF).unwrap());
+    }
+
+    #[test]
+    fn prg_mode_0_switches_8000_and_a000()
+    {
+        let mut m = setup(8, 1);
+        set_bank(&mut m, 6, 3);
+        set_bank(&mut m, 7, 9);
+
+        assert_eq!(3, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(9, m.cpu_peek(0xA000).unwrap());
+        assert_eq!(14, m.cpu_peek(0xC000).unwrap());
+    }
+
+    #[test]
+    fn prg_mode_1_swaps_8000_and_c000()
+    {
+        let mut m = setup(8, 1);
+        set_bank(&mut m, SELECT_PRG_MODE | 6, 3);
+
+        assert_eq!(14, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(3, m.cpu_peek(0xC000).unwrap());
+    }
+
+    #[test]
+    fn chr_banks_2k_and_1k()
+    {
+        let mut m = setup(2, 2);
+        set_bank(&mut m, 0, 5);
+        set_bank(&mut m, 1, 8);
+        set_bank(&mut m, 2, 1);
+        set_bank(&mut m, 5, 15);
+
+        assert_eq!(4, m.ppu_peek(0x0000).unwrap());
+        assert_eq!(5, m.ppu_peek(0x0400).unwrap());
+        assert_eq!(8, m.ppu_peek(0x0800).unwrap());
+        assert_eq!(9, m.ppu_peek(0x0C00).unwrap());
+        assert_eq!(1, m.ppu_peek(0x1000).unwrap());
+        assert_eq!(15, m.ppu_peek(0x1C00).unwrap());
+    }
+
+    #[test]
+    fn chr_a12_inversion_swaps_halves()
+    {
+        let mut m = setup(2, 2);
+        set_bank(&mut m, SELECT_CHR_A12, 6);
+        set_bank(&mut m, SELECT_CHR_A12 | 2, 3);
+
+        assert_eq!(6, m.ppu_peek(0x1000).unwrap());
+        assert_eq!(3, m.ppu_peek(0x0000).unwrap());
+    }
+
+    #[test]
+    fn a000_selects_mirroring()
+    {
+        let mut m = setup(2, 1);
+        m.cpu_write(0xA000, 1).unwrap();
+        assert_eq!(Mirroring::Horizontal, m.mirroring());
+        m.cpu_write(0xA000, 0).unwrap();
+        assert_eq!(Mirroring::Vertical, m.mirroring());
+    }
+
+    #[test]
+    fn prg_ram_protect()
+    {
+        let mut m = setup(2, 1);
+        m.cpu_write(0x6000, 0x11).unwrap();
+
+        m.cpu_write(0xA001, PRG_RAM_ENABLE | PRG_RAM_PROTECT).unwrap();
+        m.cpu_write(0x6000, 0x22).unwrap();
+        assert_eq!(0x11, m.cpu_peek(0x6000).unwrap());
+
+        m.cpu_write(0xA001, 0x00).unwrap();
+        assert_eq!(0xFF, m.cpu_open_bus_mask(0x6000));
+    }
+
+    #[test]
+    fn irq_after_latch_plus_one_rises()
+    {
+        let mut m = setup(2, 1);
+        m.cpu_write(0xC000, 3).unwrap();
+        m.cpu_write(0xC001, 0).unwrap();
+        m.cpu_write(0xE001, 0).unwrap();
+
+        // Reload to 3, then 2, 1, 0.
+        for _ in 0..3
+        {
+            m.notify_a12_rise();
+            assert!(!m.irq());
+        }
+        m.notify_a12_rise();
+        assert!(m.irq());
+
+        // $E000 acknowledges and disables.
+        m.cpu_write(0xE000, 0).unwrap();
+        assert!(!m.irq());
+        for _ in 0..8
+        {
+            m.notify_a12_rise();
+        }
+        assert!(!m.irq());
+    }
+
+    #[test]
+    fn irq_is_reported_by_bus_tick()
+    {
+        use crate::mapper::CpuPort;
+        use std::{cell::RefCell, rc::Rc};
+
+        let mapper = Rc::new(RefCell::new(setup(2, 1)));
+        let mut bus = CompositeMemory::new();
+        bus.register_range(0x4020, 0xFFFF, Box::new(CpuPort::new(mapper.clone()))).unwrap();
+        bus.write_byte(0xC000, 0).unwrap();
+        bus.write_byte(0xE001, 0).unwrap();
+
+        mapper.borrow_mut().notify_a12_rise();
+
+        match bus.tick(1)
+        {
+            MemTickResult::IRQ(lines) => assert_eq!(IRQ_LINE, lines),
+            MemTickResult::Ok => panic!("MMC3 IRQ was not reported")
+        }
+    }
+}
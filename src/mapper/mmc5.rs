@@ -0,0 +1,713 @@
+use crate::memory::*;
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::mapper::{Chr, Mapper, Nametable, RenderPhase};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+const EXRAM_SIZE: usize = 0x0400;
+
+const PRG_ROM_SELECT: u8 = 0b10000000;
+
+const IRQ_ENABLE: u8   = 0b10000000;
+const IRQ_PENDING: u8  = 0b10000000;
+const IRQ_IN_FRAME: u8 = 0b01000000;
+
+const SPLIT_ENABLE: u8 = 0b10000000;
+const SPLIT_RIGHT: u8  = 0b01000000;
+const SPLIT_TILES: u8  = 0b00011111;
+
+// ExRAM modes, $5104
+const EXRAM_NAMETABLE: u8 = 0;
+const EXRAM_ATTRIBUTES: u8 = 1;
+const EXRAM_RAM: u8 = 2;
+
+// ppu ctrl bits MMC5 watches on the CPU bus
+const PPU_SPRITE_SIZE_16: u8 = 0b00100000;
+const PPU_RENDERING: u8      = 0b00011000;
+
+// Mapper 5: MMC5. Registers at $5000-$5FFF:
+//   $5100 PRG mode       $5101 CHR mode        $5102/$5103 PRG RAM protect
+//   $5104 ExRAM mode     $5105 nametable map   $5106/$5107 fill tile/attribute
+//   $5113-$5117 PRG banks, bit 7 of $5114-$5116 selects ROM over RAM
+//   $5120-$5127 CHR set A (sprites), $5128-$512B CHR set B (background of
+//               8x16 sprite games), $5130 upper CHR bank bits
+//   $5200-$5202 vertical split   $5203/$5204 scanline IRQ
+//   $5205/$5206 8x8 multiplier   $5C00-$5FFF ExRAM
+// The expansion audio registers ($5000-$5015) are accepted and ignored.
+pub struct Mmc5
+{
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Chr,
+    exram: [u8; EXRAM_SIZE],
+    prg_mode: u8,
+    chr_mode: u8,
+    ram_protect: [u8; 2],
+    exram_mode: u8,
+    nametable_map: u8,
+    fill_tile: u8,
+    fill_attribute: u8,
+    // $5113-$5117
+    prg_banks: [u8; 5],
+    chr_a: [u16; 8],
+    chr_b: [u16; 4],
+    chr_upper: u16,
+    last_set_b: bool,
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: u8,
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    multiplicand: u8,
+    multiplier: u8,
+    // What the PPU is doing, as far as the MMC5 can tell.
+    ppu_ctrl0: u8,
+    phase: RenderPhase,
+    in_frame: bool,
+    scanline: u8,
+    tile: usize,
+    in_split: bool,
+    ext_attribute: u8
+}
+
+impl Mmc5
+{
+    pub fn new(cart: Cartridge, state: PowerOnState) -> Self
+    {
+        let mut prg_ram = vec![0; cart.prg_ram_size + cart.prg_nvram_size];
+        state.fill(&mut prg_ram);
+        let mut exram = [0; EXRAM_SIZE];
+        state.fill(&mut exram);
+
+        Mmc5 {
+            prg_rom: cart.prg_rom,
+            prg_ram,
+            chr: Chr::new(cart.chr_rom, cart.chr_ram_size, state),
+            exram,
+            prg_mode: 3,
+            chr_mode: 0,
+            ram_protect: [0, 0],
+            exram_mode: 0,
+            nametable_map: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            prg_banks: [0, 0, 0, 0, 0xFF],
+            chr_a: [0; 8],
+            chr_b: [0; 4],
+            chr_upper: 0,
+            last_set_b: false,
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+            ppu_ctrl0: 0,
+            phase: RenderPhase::Idle,
+            in_frame: false,
+            scanline: 0,
+            tile: 0,
+            in_split: false,
+            ext_attribute: 0
+        }
+    }
+
+    // The register for a CPU address in $8000-$FFFF in the current PRG mode,
+    // and the size of the window it controls.
+    fn prg_register(&self, address: usize) -> (usize, usize)
+    {
+        match (self.prg_mode & 0x03, address)
+        {
+            (0, _) => (4, 0x8000),
+            (1, 0x8000..=0xBFFF) => (2, 0x4000),
+            (1, _) => (4, 0x4000),
+            (2, 0x8000..=0xBFFF) => (2, 0x4000),
+            (2, 0xC000..=0xDFFF) => (3, 0x2000),
+            (2, _) => (4, 0x2000),
+            (_, _) => (1 + (address - 0x8000) / PRG_BANK_SIZE, 0x2000)
+        }
+    }
+
+    // (ROM, offset) for a CPU address in $6000-$FFFF. $5117 and $6000-$7FFF
+    // can only be ROM and RAM respectively.
+    fn prg_target(&self, address: usize) -> (bool, usize)
+    {
+        if address < 0x8000
+        {
+            return (false, (self.prg_banks[0] & 0x07) as usize * PRG_BANK_SIZE + (address & 0x1FFF));
+        }
+
+        let (register, size) = self.prg_register(address);
+        let value = self.prg_banks[register];
+        let rom = register == 4 || value & PRG_ROM_SELECT != 0;
+        let bank = (value & !PRG_ROM_SELECT) as usize & !(size / PRG_BANK_SIZE - 1);
+        (rom, bank * PRG_BANK_SIZE + (address & (size - 1)))
+    }
+
+    fn prg_ram_writable(&self) -> bool
+    {
+        self.ram_protect[0] & 0x03 == 0x02 && self.ram_protect[1] & 0x03 == 0x01
+    }
+
+    // With 8x16 sprites the background has its own CHR set while
+    // rendering. Otherwise whichever set was written last is used.
+    fn use_set_b(&self) -> bool
+    {
+        let tall = self.ppu_ctrl0 & PPU_SPRITE_SIZE_16 != 0;
+        if tall && self.phase != RenderPhase::Idle
+        {
+            return self.phase == RenderPhase::Background;
+        }
+        self.last_set_b
+    }
+
+    // 1 KiB CHR bank for a PPU address in $0000-$1FFF.
+    fn chr_bank(&self, address: usize) -> usize
+    {
+        let slot = address / CHR_BANK_SIZE;
+        if self.use_set_b()
+        {
+            // Set B only has registers for $0000-$0FFF, the upper half
+            // shows the same banks.
+            let s = slot % 4;
+            let b = &self.chr_b;
+            return match self.chr_mode & 0x03
+            {
+                0 => b[3] as usize * 8 + slot,
+                1 => b[3] as usize * 4 + s,
+                2 => b[1 + (s / 2) * 2] as usize * 2 + s % 2,
+                _ => b[s] as usize
+            };
+        }
+
+        let a = &self.chr_a;
+        match self.chr_mode & 0x03
+        {
+            0 => a[7] as usize * 8 + slot,
+            1 => a[3 + (slot / 4) * 4] as usize * 4 + slot % 4,
+            2 => a[1 + (slot / 2) * 2] as usize * 2 + slot % 2,
+            _ => a[slot] as usize
+        }
+    }
+
+    fn chr_offset(&self, address: usize) -> usize
+    {
+        if self.phase == RenderPhase::Background
+        {
+            if self.in_split
+            {
+                let fine_y = (self.split_y() % 8) as usize;
+                return self.split_bank as usize * 0x1000 + (address & 0x0FF8) + fine_y;
+            }
+            if self.exram_mode == EXRAM_ATTRIBUTES
+            {
+                let bank = ((self.chr_upper as usize) << 6) | (self.ext_attribute & 0x3F) as usize;
+                return bank * 0x1000 + (address & 0x0FFF);
+            }
+        }
+        self.chr_bank(address) * CHR_BANK_SIZE + (address & 0x03FF)
+    }
+
+    // Line inside the split region, it scrolls on its own.
+    fn split_y(&self) -> u16
+    {
+        (self.scanline.saturating_sub(1) as u16 + self.split_scroll as u16) % 240
+    }
+
+    fn nametable_source(&self, address: usize) -> u8
+    {
+        (self.nametable_map >> (((address >> 10) & 0x03) * 2)) & 0x03
+    }
+
+    fn write_register(&mut self, address: usize, data: u8)
+    {
+        match address
+        {
+            0x5100 => self.prg_mode = data & 0x03,
+            0x5101 => self.chr_mode = data & 0x03,
+            0x5102 => self.ram_protect[0] = data,
+            0x5103 => self.ram_protect[1] = data,
+            0x5104 => self.exram_mode = data & 0x03,
+            0x5105 => self.nametable_map = data,
+            0x5106 => self.fill_tile = data,
+            0x5107 => self.fill_attribute = data & 0x03,
+            0x5113..=0x5117 => self.prg_banks[address - 0x5113] = data,
+            0x5120..=0x5127 => {
+                self.chr_a[address - 0x5120] = (self.chr_upper << 8) | data as u16;
+                self.last_set_b = false;
+            },
+            0x5128..=0x512B => {
+                self.chr_b[address - 0x5128] = (self.chr_upper << 8) | data as u16;
+                self.last_set_b = true;
+            },
+            0x5130 => self.chr_upper = (data & 0x03) as u16,
+            0x5200 => self.split_control = data,
+            0x5201 => self.split_scroll = data,
+            0x5202 => self.split_bank = data,
+            0x5203 => self.irq_compare = data,
+            0x5204 => self.irq_enabled = data & IRQ_ENABLE != 0,
+            0x5205 => self.multiplicand = data,
+            0x5206 => self.multiplier = data,
+            0x5C00..=0x5FFF => {
+                // In the nametable modes ExRAM is only written while the
+                // PPU renders, other writes store 0.
+                let index = address - 0x5C00;
+                match self.exram_mode
+                {
+                    EXRAM_NAMETABLE | EXRAM_ATTRIBUTES => self.exram[index] = if self.in_frame { data } else { 0 },
+                    EXRAM_RAM => self.exram[index] = data,
+                    _ => {}
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn read_register(&self, address: usize) -> Option<u8>
+    {
+        let product = self.multiplicand as u16 * self.multiplier as u16;
+        match address
+        {
+            0x5204 => {
+                let pending = if self.irq_pending { IRQ_PENDING } else { 0 };
+                let in_frame = if self.in_frame { IRQ_IN_FRAME } else { 0 };
+                Some(pending | in_frame)
+            },
+            0x5205 => Some(product as u8),
+            0x5206 => Some((product >> 8) as u8),
+            0x5C00..=0x5FFF if self.exram_mode >= EXRAM_RAM => Some(self.exram[address - 0x5C00]),
+            _ => None
+        }
+    }
+}
+
+impl Mapper for Mmc5
+{
+    fn cpu_read(&mut self, address: usize) -> Result<u8, MemError>
+    {
+        let data = self.cpu_peek(address);
+        // Reading the status acknowledges the IRQ.
+        if address == 0x5204
+        {
+            self.irq_pending = false;
+        }
+        data
+    }
+
+    fn cpu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        match address
+        {
+            0x5000..=0x5FFF => self.write_register(address, data),
+            0x6000..=0xDFFF if self.prg_ram_writable() && !self.prg_ram.is_empty() => {
+                let (rom, offset) = self.prg_target(address);
+                if !rom
+                {
+                    let len = self.prg_ram.len();
+                    self.prg_ram[offset % len] = data;
+                }
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn cpu_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        match address
+        {
+            0x5000..=0x5FFF => Ok(self.read_register(address).unwrap_or(0x00)),
+            0x6000..=0xFFFF => {
+                let (rom, offset) = self.prg_target(address);
+                if rom
+                {
+                    Ok(self.prg_rom[offset % self.prg_rom.len()])
+                }
+                else if self.prg_ram.is_empty()
+                {
+                    Ok(0x00)
+                }
+                else
+                {
+                    Ok(self.prg_ram[offset % self.prg_ram.len()])
+                }
+            },
+            _ => Ok(0x00)
+        }
+    }
+
+    fn cpu_open_bus_mask(&self, address: usize) -> u8
+    {
+        match address
+        {
+            0x5000..=0x5FFF if self.read_register(address).is_some() => 0x00,
+            0x6000..=0xFFFF if self.prg_target(address).0 || !self.prg_ram.is_empty() => 0x00,
+            _ => 0xFF
+        }
+    }
+
+    fn ppu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        if address < 0x2000
+        {
+            let offset = self.chr_offset(address);
+            self.chr.write(offset, data);
+        }
+        else if self.nametable_source(address) == 2 && self.exram_mode <= EXRAM_ATTRIBUTES
+        {
+            self.exram[address & 0x03FF] = data;
+        }
+        Ok(())
+    }
+
+    fn ppu_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        if address < 0x2000
+        {
+            return Ok(self.chr.read(self.chr_offset(address)));
+        }
+
+        let attribute = address & 0x03FF >= 0x03C0;
+        match self.nametable_source(address)
+        {
+            2 if self.exram_mode <= EXRAM_ATTRIBUTES => Ok(self.exram[address & 0x03FF]),
+            3 if attribute => Ok(self.fill_attribute * 0x55),
+            3 => Ok(self.fill_tile),
+            _ => Ok(0x00)
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring
+    {
+        match self.nametable_map
+        {
+            0x00 => Mirroring::SingleScreenA,
+            0x55 => Mirroring::SingleScreenB,
+            0x50 => Mirroring::Horizontal,
+            0x44 => Mirroring::Vertical,
+            _ => Mirroring::FourScreen
+        }
+    }
+
+    // Each nametable can be either CIRAM page, ExRAM or the fill tile.
+    fn nametable(&self, address: usize) -> Nametable
+    {
+        match self.nametable_source(address)
+        {
+            source @ 0..=1 => Nametable::Ciram(source as usize),
+            _ => Nametable::Cartridge
+        }
+    }
+
+    fn nametable_fetch(&mut self, address: usize, data: u8) -> u8
+    {
+        let index = address & 0x03FF;
+        if index < 0x03C0
+        {
+            let x = self.tile;
+            self.tile += 1;
+
+            let threshold = (self.split_control & SPLIT_TILES) as usize;
+            let right = self.split_control & SPLIT_RIGHT != 0;
+            self.in_split = self.split_control & SPLIT_ENABLE != 0 && self.exram_mode <= EXRAM_ATTRIBUTES
+                            && (if right { x >= threshold } else { x < threshold });
+
+            if self.in_split
+            {
+                return self.exram[(self.split_y() as usize / 8) * 32 + x % 32];
+            }
+            self.ext_attribute = self.exram[index];
+            return data;
+        }
+
+        if self.in_split
+        {
+            let x = self.tile.saturating_sub(1) % 32;
+            return self.exram[0x03C0 + (self.split_y() as usize / 32) * 8 + x / 4];
+        }
+        if self.exram_mode == EXRAM_ATTRIBUTES
+        {
+            return (self.ext_attribute >> 6) * 0x55;
+        }
+        data
+    }
+
+    // The first background fetches after the PPU was idle start a frame,
+    // every further line clocks the scanline counter.
+    fn notify_render_phase(&mut self, phase: RenderPhase)
+    {
+        self.phase = phase;
+        match phase
+        {
+            RenderPhase::Background if !self.in_frame => {
+                self.in_frame = true;
+                self.scanline = 0;
+                self.tile = 0;
+            },
+            RenderPhase::Background => {
+                self.scanline = self.scanline.wrapping_add(1);
+                self.tile = 0;
+                if self.scanline == self.irq_compare && self.irq_compare != 0
+                {
+                    self.irq_pending = true;
+                }
+            },
+            RenderPhase::Sprites => self.in_split = false,
+            RenderPhase::Idle => {
+                self.in_frame = false;
+                self.in_split = false;
+            }
+        }
+    }
+
+    fn notify_ppu_ctrl(&mut self, ctrl0: u8, ctrl1: u8)
+    {
+        self.ppu_ctrl0 = ctrl0;
+        if ctrl1 & PPU_RENDERING == 0
+        {
+            self.notify_render_phase(RenderPhase::Idle);
+        }
+    }
+
+    fn irq(&self) -> bool
+    {
+        self.irq_pending && self.irq_enabled
+    }
+
+    fn prg_ram(&mut self) -> &mut [u8]
+    {
+        &mut self.prg_ram
+    }
+
+    fn name(&self) -> &'static str
+    {
+        "MMC5"
+    }
+}
+
+#[cfg(test)]
+mod mmc5tests
+{
+    use crate::mapper::mmc5::*;
+    use crate::mapper::test_rom;
+
+    fn setup() -> Mmc5
+    {
+        let mut cart = test_rom(5, PRG_BANK_SIZE, 32, CHR_BANK_SIZE, 256);
+        cart.prg_ram_size = 0x10000;
+        let mut m = Mmc5::new(cart, PowerOnState::Zeros);
+        m.cpu_write(0x5102, 0x02).unwrap();
+        m.cpu_write(0x5103, 0x01).unwrap();
+        m
+    }
+
+    #[test]
+    fn powers_up_with_last_bank_everywhere()
+    {
+        let m = setup();
+
+        assert_eq!(31, m.cpu_peek(0xE000).unwrap());
+        assert_eq!(31, m.cpu_peek(0xFFFF).unwrap());
+    }
+
+    #[test]
+    fn prg_mode_0_switches_32k()
+    {
+        let mut m = setup();
+        m.cpu_write(0x5100, 0).unwrap();
+        m.cpu_write(0x5117, 0x8D).unwrap();
+
+        assert_eq!(12, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(15, m.cpu_peek(0xE000).unwrap());
+    }
+
+    #[test]
+    fn prg_mode_2_mixes_16k_and_8k()
+    {
+        let mut m = setup();
+        m.cpu_write(0x5100, 2).unwrap();
+        m.cpu_write(0x5115, 0x85).unwrap();
+        m.cpu_write(0x5116, 0x89).unwrap();
+
+        assert_eq!(4, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(5, m.cpu_peek(0xA000).unwrap());
+        assert_eq!(9, m.cpu_peek(0xC000).unwrap());
+        assert_eq!(31, m.cpu_peek(0xE000).unwrap());
+    }
+
+    #[test]
+    fn prg_mode_3_can_map_ram_into_rom_area()
+    {
+        let mut m = setup();
+        m.cpu_write(0x5114, 0x02).unwrap();
+        m.cpu_write(0x8010, 0x5A).unwrap();
+        m.cpu_write(0x5113, 0x02).unwrap();
+
+        assert_eq!(0x5A, m.cpu_peek(0x6010).unwrap());
+        assert_eq!(0x5A, m.cpu_peek(0x8010).unwrap());
+    }
+
+    #[test]
+    fn prg_ram_needs_both_protect_registers()
+    {
+        let mut m = setup();
+        m.cpu_write(0x6000, 0x11).unwrap();
+        m.cpu_write(0x5103, 0x00).unwrap();
+        m.cpu_write(0x6000, 0x22).unwrap();
+
+        assert_eq!(0x11, m.cpu_peek(0x6000).unwrap());
+    }
+
+    #[test]
+    fn chr_modes()
+    {
+        let mut m = setup();
+        m.cpu_write(0x5101, 3).unwrap();
+        m.cpu_write(0x5126, 0x42).unwrap();
+        assert_eq!(0x42, m.ppu_peek(0x1800).unwrap());
+
+        m.cpu_write(0x5101, 1).unwrap();
+        m.cpu_write(0x5123, 0x03).unwrap();
+        assert_eq!(0x0D, m.ppu_peek(0x0400).unwrap());
+
+        m.cpu_write(0x5101, 0).unwrap();
+        m.cpu_write(0x5127, 0x02).unwrap();
+        assert_eq!(0x17, m.ppu_peek(0x1C00).unwrap());
+    }
+
+    #[test]
+    fn upper_chr_bits_extend_bank_numbers()
+    {
+        let mut m = setup();
+        m.cpu_write(0x5101, 3).unwrap();
+        m.cpu_write(0x5130, 0x01).unwrap();
+        m.cpu_write(0x5120, 0x03).unwrap();
+
+        assert_eq!(0x103, m.chr_bank(0x0000));
+    }
+
+    #[test]
+    fn tall_sprites_use_set_b_for_background()
+    {
+        let mut m = setup();
+        m.cpu_write(0x5101, 3).unwrap();
+        m.cpu_write(0x5120, 0x10).unwrap();
+        m.cpu_write(0x5128, 0x20).unwrap();
+        m.notify_ppu_ctrl(PPU_SPRITE_SIZE_16, PPU_RENDERING);
+
+        m.notify_render_phase(RenderPhase::Background);
+        assert_eq!(0x20, m.ppu_read(0x0000).unwrap());
+        assert_eq!(0x20, m.ppu_read(0x1000).unwrap());
+        m.notify_render_phase(RenderPhase::Sprites);
+        assert_eq!(0x10, m.ppu_read(0x0000).unwrap());
+
+        // Outside rendering the last written set counts.
+        m.notify_render_phase(RenderPhase::Idle);
+        assert_eq!(0x20, m.ppu_read(0x0000).unwrap());
+    }
+
+    #[test]
+    fn nametable_mapping_and_fill_mode()
+    {
+        let mut m = setup();
+        // $2000 CIRAM A, $2400 CIRAM B, $2800 ExRAM, $2C00 fill
+        m.cpu_write(0x5105, 0b11_10_01_00).unwrap();
+        m.cpu_write(0x5106, 0x77).unwrap();
+        m.cpu_write(0x5107, 0x02).unwrap();
+        m.ppu_write(0x2805, 0x33).unwrap();
+
+        assert_eq!(Nametable::Ciram(0), m.nametable(0x2000));
+        assert_eq!(Nametable::Ciram(1), m.nametable(0x2400));
+        assert_eq!(Nametable::Cartridge, m.nametable(0x2800));
+        assert_eq!(0x33, m.ppu_peek(0x2805).unwrap());
+        assert_eq!(0x77, m.ppu_peek(0x2C10).unwrap());
+        assert_eq!(0xAA, m.ppu_peek(0x2FC0).unwrap());
+    }
+
+    #[test]
+    fn exram_as_cpu_ram()
+    {
+        let mut m = setup();
+        m.cpu_write(0x5C00, 0x12).unwrap();
+        assert_eq!(0xFF, m.cpu_open_bus_mask(0x5C00));
+
+        m.cpu_write(0x5104, 2).unwrap();
+        m.cpu_write(0x5C00, 0x12).unwrap();
+        assert_eq!(0x12, m.cpu_peek(0x5C00).unwrap());
+
+        m.cpu_write(0x5104, 3).unwrap();
+        m.cpu_write(0x5C00, 0x34).unwrap();
+        assert_eq!(0x12, m.cpu_peek(0x5C00).unwrap());
+    }
+
+    #[test]
+    fn extended_attributes_select_bank_and_palette()
+    {
+        let mut m = setup();
+        m.cpu_write(0x5104, 2).unwrap();
+        m.cpu_write(0x5C05, 0xC3).unwrap();
+        m.cpu_write(0x5104, 1).unwrap();
+
+        m.notify_render_phase(RenderPhase::Background);
+        m.nametable_fetch(0x2005, 0x00);
+        assert_eq!(0xFF, m.nametable_fetch(0x23C1, 0x00));
+        // 4 KiB bank 3 is 1 KiB bank 12.
+        assert_eq!(12, m.ppu_read(0x0010).unwrap());
+    }
+
+    #[test]
+    fn split_replaces_left_tiles()
+    {
+        let mut m = setup();
+        m.cpu_write(0x5104, 2).unwrap();
+        m.cpu_write(0x5C01, 0x99).unwrap();
+        m.cpu_write(0x5104, 0).unwrap();
+        m.cpu_write(0x5200, SPLIT_ENABLE | 2).unwrap();
+        m.cpu_write(0x5202, 0x05).unwrap();
+
+        m.notify_render_phase(RenderPhase::Background);
+        assert_eq!(0x00, m.nametable_fetch(0x2000, 0x00));
+        assert_eq!(0x99, m.nametable_fetch(0x2001, 0x00));
+        // 4 KiB bank 5 is 1 KiB bank 20.
+        assert_eq!(20, m.ppu_read(0x0000).unwrap());
+        assert_eq!(0x44, m.nametable_fetch(0x2002, 0x44));
+    }
+
+    #[test]
+    fn scanline_irq()
+    {
+        let mut m = setup();
+        m.cpu_write(0x5203, 3).unwrap();
+        m.cpu_write(0x5204, IRQ_ENABLE).unwrap();
+
+        m.notify_render_phase(RenderPhase::Background);
+        assert_eq!(IRQ_IN_FRAME, m.cpu_peek(0x5204).unwrap());
+        for _ in 0..2
+        {
+            m.notify_render_phase(RenderPhase::Background);
+        }
+        assert!(!m.irq());
+        m.notify_render_phase(RenderPhase::Background);
+        assert!(m.irq());
+
+        assert_eq!(IRQ_PENDING | IRQ_IN_FRAME, m.cpu_read(0x5204).unwrap());
+        assert!(!m.irq());
+
+        m.notify_render_phase(RenderPhase::Idle);
+        assert_eq!(0, m.cpu_peek(0x5204).unwrap());
+    }
+
+    #[test]
+    fn multiplier()
+    {
+        let mut m = setup();
+        m.cpu_write(0x5205, 200).unwrap();
+        m.cpu_write(0x5206, 100).unwrap();
+
+        assert_eq!(20000 & 0xFF, m.cpu_peek(0x5205).unwrap() as u16);
+        assert_eq!(20000 >> 8, m.cpu_peek(0x5206).unwrap() as u16);
+    }
+}
@@ -0,0 +1,437 @@
+use crate::memory::*;
+use crate::cartridge::{Cartridge, CartridgeError, Mirroring};
+use std::{cell::RefCell, rc::Rc};
+
+pub mod nrom;
+pub mod mmc1;
+pub mod discrete;
+pub mod mmc3;
+pub mod mmc2;
+pub mod vrc;
+pub mod vrc6;
+pub mod mmc5;
+pub mod fme7;
+pub mod namco163;
+
+// The cartridge board. Everything the CPU sees at $4020-$FFFF and the PPU
+// sees at $0000-$1FFF goes through here, so bank switching, PRG RAM and IRQ
+// counters all live in the mapper. All addresses are absolute, i.e. a read
+// of the reset vector arrives as $FFFC.
+//
+// The nametables at $2000-$3EFF are CIRAM inside the console, the mapper
+// decides how they are wired through nametable(). Boards with their own
+// nametable memory route these accesses to ppu_read/ppu_write instead.
+pub trait Mapper
+{
+    fn cpu_read(&mut self, address: usize) -> Result<u8, MemError>
+    {
+        self.cpu_peek(address)
+    }
+
+    fn cpu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>;
+
+    // Reads without side effects, for debuggers and the bus' peek.
+    fn cpu_peek(&self, address: usize) -> Result<u8, MemError>;
+
+    // Bits the board does not drive for a CPU read, see Memory::open_bus_mask.
+    fn cpu_open_bus_mask(&self, _address: usize) -> u8
+    {
+        0x00
+    }
+
+    fn ppu_read(&mut self, address: usize) -> Result<u8, MemError>
+    {
+        self.ppu_peek(address)
+    }
+
+    fn ppu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>;
+
+    fn ppu_peek(&self, address: usize) -> Result<u8, MemError>;
+
+    fn mirroring(&self) -> Mirroring;
+
+    // Where a PPU access to the nametable at address goes.
+    fn nametable(&self, address: usize) -> Nametable
+    {
+        Nametable::Ciram(ciram_page(self.mirroring(), address))
+    }
+
+    // Called for every nametable and attribute fetch during rendering with
+    // the byte the PPU got. Mappers that replace what the PPU sees while
+    // drawing (MMC5 split screen and extended attributes) return something
+    // else.
+    fn nametable_fetch(&mut self, _address: usize, data: u8) -> u8
+    {
+        data
+    }
+
+    // Called by the PPU when it starts fetching background or sprite tiles
+    // for a line, and with Idle when it stops rendering for the frame.
+    fn notify_render_phase(&mut self, _phase: RenderPhase)
+    {
+    }
+
+    // Called when the CPU writes $2000 or $2001. The cartridge connector
+    // has no such signal, MMC5 snoops the CPU bus to get these.
+    fn notify_ppu_ctrl(&mut self, _ctrl0: u8, _ctrl1: u8)
+    {
+    }
+
+    // Called by the PPU once per scanline.
+    fn notify_scanline(&mut self)
+    {
+    }
+
+    // Called by the PPU when its address line A12 goes from low to high,
+    // which happens when it switches from fetching $0xxx to $1xxx tiles.
+    fn notify_a12_rise(&mut self)
+    {
+    }
+
+    // Called by the PPU after it fetched pattern data for rendering.
+    fn notify_pattern_fetch(&mut self, _address: usize)
+    {
+    }
+
+    // Called with the number of CPU cycles that passed since the last call.
+    fn notify_cpu_cycles(&mut self, _cycles: u32)
+    {
+    }
+
+    // State of the cartridge's IRQ output.
+    fn irq(&self) -> bool
+    {
+        false
+    }
+
+    // The work RAM at $6000-$7FFF, for battery saves. Boards without any
+    // return an empty slice.
+    fn prg_ram(&mut self) -> &mut [u8]
+    {
+        &mut []
+    }
+
+    fn name(&self) -> &'static str;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Nametable
+{
+    // One of the 1 KiB CIRAM pages, page 2 and 3 are the extra VRAM of four
+    // screen boards.
+    Ciram(usize),
+    Cartridge
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderPhase
+{
+    Background,
+    Sprites,
+    Idle
+}
+
+// CIRAM page for a nametable address with the given mirroring. The four
+// nametables are $2000, $2400, $2800 and $2C00.
+pub fn ciram_page(mirroring: Mirroring, address: usize) -> usize
+{
+    let quadrant = (address >> 10) & 0x03;
+    match mirroring
+    {
+        Mirroring::Horizontal => quadrant >> 1,
+        Mirroring::Vertical => quadrant & 1,
+        Mirroring::SingleScreenA => 0,
+        Mirroring::SingleScreenB => 1,
+        Mirroring::FourScreen => quadrant
+    }
+}
+
+// The CPU bus and the PPU both talk to the same board.
+pub type SharedMapper = Rc<RefCell<dyn Mapper>>;
+
+const CPU_BASE: usize = 0x4020;
+const DEFAULT_CHR_RAM_SIZE: usize = 0x2000;
+
+// Builds the board described by the header. The cartridge is consumed, its
+// ROM data moves into the mapper.
+pub fn create(cart: Cartridge, state: PowerOnState) -> Result<SharedMapper, CartridgeError>
+{
+    // The boards compute PRG offsets modulo the ROM size.
+    if cart.prg_rom.is_empty()
+    {
+        return Err(CartridgeError::TooSmall { section: "PRG ROM", minimum: 1, found: 0 });
+    }
+
+    match cart.mapper
+    {
+        0 => Ok(Rc::new(RefCell::new(nrom::Nrom::new(cart, state)))),
+        1 => Ok(Rc::new(RefCell::new(mmc1::Mmc1::new(cart, state)))),
+        2 => {
+            let conflicts = bus_conflicts(&cart, true);
+            Ok(Rc::new(RefCell::new(discrete::Discrete::new(discrete::Board::Uxrom, cart, state, conflicts)?)))
+        },
+        3 => {
+            let conflicts = bus_conflicts(&cart, true);
+            Ok(Rc::new(RefCell::new(discrete::Discrete::new(discrete::Board::Cnrom, cart, state, conflicts)?)))
+        },
+        4 => Ok(Rc::new(RefCell::new(mmc3::Mmc3::new(cart, state)))),
+        7 => {
+            let conflicts = bus_conflicts(&cart, false);
+            Ok(Rc::new(RefCell::new(discrete::Discrete::new(discrete::Board::Axrom, cart, state, conflicts)?)))
+        },
+        5 => Ok(Rc::new(RefCell::new(mmc5::Mmc5::new(cart, state)))),
+        9 | 10 => Ok(Rc::new(RefCell::new(mmc2::Mmc2::new(cart, state)))),
+        19 => Ok(Rc::new(RefCell::new(namco163::Namco163::new(cart, state)?))),
+        21 | 22 | 23 | 25 => Ok(Rc::new(RefCell::new(vrc::Vrc24::new(cart, state)))),
+        24 | 26 => Ok(Rc::new(RefCell::new(vrc6::Vrc6::new(cart, state)?))),
+        69 => Ok(Rc::new(RefCell::new(fme7::Fme7::new(cart, state)?))),
+        66 => {
+            let conflicts = bus_conflicts(&cart, true);
+            Ok(Rc::new(RefCell::new(discrete::Discrete::new(discrete::Board::Gxrom, cart, state, conflicts)?)))
+        },
+        x => Err(CartridgeError::UnsupportedMapper(x))
+    }
+}
+
+// NES 2.0 submappers 1 and 2 of the discrete boards say whether there are
+// bus conflicts. Without that we go with what most boards of the kind do,
+// e.g. UNROM has them, AOROM does not.
+fn bus_conflicts(cart: &Cartridge, default: bool) -> bool
+{
+    match cart.submapper
+    {
+        1 => false,
+        2 => true,
+        _ => default
+    }
+}
+
+// Puts a mapper on the CPU bus, register it at $4020-$FFFF. Writes to the
+// ROM area go to the board's registers, the ROM itself never changes.
+pub struct CpuPort
+{
+    mapper: SharedMapper
+}
+
+impl CpuPort
+{
+    pub fn new(mapper: SharedMapper) -> Self
+    {
+        CpuPort { mapper }
+    }
+}
+
+impl Memory for CpuPort
+{
+    fn read_byte(&mut self, address: usize) -> Result<u8, MemError>
+    {
+        self.mapper.borrow_mut().cpu_read(address + CPU_BASE).map_err(|e| e.with_address(address))
+    }
+
+    fn write_byte(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        self.mapper.borrow_mut().cpu_write(address + CPU_BASE, data).map_err(|e| e.with_address(address))
+    }
+
+    fn peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        self.mapper.borrow().cpu_peek(address + CPU_BASE).map_err(|e| e.with_address(address))
+    }
+
+    fn open_bus_mask(&self, address: usize) -> u8
+    {
+        self.mapper.borrow().cpu_open_bus_mask(address + CPU_BASE)
+    }
+
+    fn tick(&mut self, clock_ticks: u32) -> MemTickResult
+    {
+        let mut mapper = self.mapper.borrow_mut();
+        mapper.notify_cpu_cycles(clock_ticks);
+        if mapper.irq()
+        {
+            return MemTickResult::IRQ(IRQ_LINE);
+        }
+        MemTickResult::Ok
+    }
+
+    fn name(&self) -> &'static str
+    {
+        "Cartridge"
+    }
+}
+
+// CHR ROM, or CHR RAM on boards that have no CHR ROM. Offsets wrap around,
+// so a bank number beyond the end works like on a board with fewer address
+// lines connected.
+pub struct Chr
+{
+    data: Vec<u8>,
+    writable: bool
+}
+
+impl Chr
+{
+    pub fn new(chr_rom: Vec<u8>, chr_ram_size: usize, state: PowerOnState) -> Self
+    {
+        if !chr_rom.is_empty()
+        {
+            return Chr { data: chr_rom, writable: false };
+        }
+
+        let size = if chr_ram_size == 0 { DEFAULT_CHR_RAM_SIZE } else { chr_ram_size };
+        let mut data = vec![0; size];
+        state.fill(&mut data);
+        Chr { data, writable: true }
+    }
+
+    pub fn read(&self, offset: usize) -> u8
+    {
+        self.data[offset % self.data.len()]
+    }
+
+    // Writes to CHR ROM end up nowhere.
+    pub fn write(&mut self, offset: usize, data: u8)
+    {
+        if self.writable
+        {
+            let len = self.data.len();
+            self.data[offset % len] = data;
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize
+    {
+        self.data.len()
+    }
+}
+
+// An iNES file for the mapper tests. Every PRG and every CHR bank is
+// filled with its number, so a read tells which bank is mapped.
+#[cfg(test)]
+pub fn test_rom(mapper: u8, prg_bank_size: usize, prg_banks: usize, chr_bank_size: usize, chr_banks: usize) -> Cartridge
+{
+    let prg_size = prg_bank_size * prg_banks;
+    let chr_size = chr_bank_size * chr_banks;
+    let mut rom = vec![b'N', b'E', b'S', 0x1A, (prg_size / 0x4000) as u8, (chr_size / 0x2000) as u8, (mapper & 0x0F) << 4, mapper & 0xF0, 0, 0, 0, 0, 0, 0, 0, 0];
+    for i in 0..prg_banks
+    {
+        rom.extend(vec![i as u8; prg_bank_size]);
+    }
+    for i in 0..chr_banks
+    {
+        rom.extend(vec![i as u8; chr_bank_size]);
+    }
+    Cartridge::from_bytes(&rom).unwrap()
+}
+
+#[cfg(test)]
+mod mappertests
+{
+    use crate::mapper::*;
+
+    struct IrqStub
+    {
+        cycles: u32
+    }
+
+    impl Mapper for IrqStub
+    {
+        fn cpu_write(&mut self, _address: usize, _data: u8) -> Result<(), MemError> { Ok(()) }
+        fn cpu_peek(&self, address: usize) -> Result<u8, MemError> { Ok((address >> 8) as u8) }
+        fn ppu_write(&mut self, _address: usize, _data: u8) -> Result<(), MemError> { Ok(()) }
+        fn ppu_peek(&self, _address: usize) -> Result<u8, MemError> { Ok(0) }
+        fn mirroring(&self) -> Mirroring { Mirroring::Horizontal }
+        fn notify_cpu_cycles(&mut self, cycles: u32) { self.cycles += cycles; }
+        fn irq(&self) -> bool { self.cycles >= 100 }
+        fn name(&self) -> &'static str { "IrqStub" }
+    }
+
+    #[test]
+    fn cpu_port_passes_absolute_addresses()
+    {
+        let mut m = CompositeMemory::new();
+        m.register_range(0x4020, 0xFFFF, Box::new(CpuPort::new(Rc::new(RefCell::new(IrqStub { cycles: 0 }))))).unwrap();
+
+        assert_eq!(0xFF, m.read_byte(0xFFFC).unwrap());
+        assert_eq!(0x60, m.read_byte(0x6000).unwrap());
+    }
+
+    fn tick_lines(m: &mut CompositeMemory, clock_ticks: u32) -> u8
+    {
+        match m.tick(clock_ticks)
+        {
+            MemTickResult::Ok => 0,
+            MemTickResult::IRQ(x) => x
+        }
+    }
+
+    #[test]
+    fn cpu_port_reports_mapper_irq()
+    {
+        let mut m = CompositeMemory::new();
+        m.register_range(0x4020, 0xFFFF, Box::new(CpuPort::new(Rc::new(RefCell::new(IrqStub { cycles: 0 }))))).unwrap();
+
+        assert_eq!(0, tick_lines(&mut m, 60));
+        assert_eq!(IRQ_LINE, tick_lines(&mut m, 60));
+    }
+
+    #[test]
+    fn rejects_empty_prg_rom()
+    {
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        rom.extend(vec![0x00; 0x2000]);
+
+        match create(Cartridge::from_bytes(&rom).unwrap(), PowerOnState::Zeros)
+        {
+            Err(e) => assert_eq!(CartridgeError::TooSmall { section: "PRG ROM", minimum: 1, found: 0 }, e),
+            Ok(_) => panic!("Empty PRG ROM was accepted")
+        }
+    }
+
+    #[test]
+    fn submapper_selects_bus_conflicts()
+    {
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0, 0x08, 0x10, 0, 0, 0, 0, 0, 0, 0];
+        rom.extend(vec![0x00; 0x4000 + 0x2000]);
+        let mut cart = Cartridge::from_bytes(&rom).unwrap();
+
+        assert!(!bus_conflicts(&cart, true));
+        cart.submapper = 2;
+        assert!(bus_conflicts(&cart, false));
+        cart.submapper = 0;
+        assert!(bus_conflicts(&cart, true));
+    }
+
+    #[test]
+    fn ciram_page_follows_mirroring()
+    {
+        let pages = |mirroring| [0x2000, 0x2400, 0x2800, 0x2C00].iter().map(|&a| ciram_page(mirroring, a)).collect::<Vec<_>>();
+
+        assert_eq!(vec![0, 0, 1, 1], pages(Mirroring::Horizontal));
+        assert_eq!(vec![0, 1, 0, 1], pages(Mirroring::Vertical));
+        assert_eq!(vec![0, 0, 0, 0], pages(Mirroring::SingleScreenA));
+        assert_eq!(vec![1, 1, 1, 1], pages(Mirroring::SingleScreenB));
+        assert_eq!(vec![0, 1, 2, 3], pages(Mirroring::FourScreen));
+        assert_eq!(1, ciram_page(Mirroring::Vertical, 0x37FF));
+    }
+
+    #[test]
+    fn chr_ram_when_no_chr_rom()
+    {
+        let mut chr = Chr::new(vec![], 0, PowerOnState::Zeros);
+        chr.write(0x1FFF, 0x24);
+
+        assert_eq!(0x2000, chr.len());
+        assert_eq!(0x24, chr.read(0x1FFF));
+    }
+
+    #[test]
+    fn chr_rom_ignores_writes()
+    {
+        let mut chr = Chr::new(vec![0x42; 0x2000], 0, PowerOnState::Zeros);
+        chr.write(0x0000, 0x00);
+
+        assert_eq!(0x42, chr.read(0x0000));
+    }
+}
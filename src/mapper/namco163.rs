@@ -0,0 +1,381 @@
+use crate::memory::*;
+use crate::cartridge::{Cartridge, CartridgeError, Mirroring};
+use crate::mapper::{Chr, Mapper, Nametable};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+const INTERNAL_RAM_SIZE: usize = 0x80;
+
+// Bank numbers from here on select a CIRAM page instead of CHR ROM.
+const CIRAM_BANKS: u8 = 0xE0;
+
+const IRQ_ENABLE: u8 = 0b10000000;
+const IRQ_COUNTER_MAX: u16 = 0x7FFF;
+
+const AUTO_INCREMENT: u8 = 0b10000000;
+const RAM_ADDRESS: u8    = 0b01111111;
+// Writes to PRG RAM need $4x in the upper nibble of $F800, the low bits
+// protect each 2 KiB window.
+const WRITE_ENABLE_MASK: u8 = 0xF0;
+const WRITE_ENABLE: u8      = 0x40;
+
+// Mapper 19: Namco 163.
+//   $4800-$4FFF internal RAM data port (wavetables and audio registers)
+//   $5000/$5800 IRQ counter low / high bits and enable
+//   $8000-$BFFF CHR banks for $0000-$1FFF, 1 KiB each in steps of $800
+//   $C000-$DFFF nametable banks for $2000-$2FFF, 1 KiB each in steps of $800
+//   $E000/$E800/$F000 8 KiB PRG banks at $8000/$A000/$C000, $E000 is fixed
+//   $F800       PRG RAM write protect and internal RAM address
+// Nametables can come from CHR ROM, which is what the large-map games use.
+// Bank numbers $E0 and up select CIRAM instead. In the pattern tables
+// that would need the console's CIRAM on the cartridge side, which no
+// licensed game relies on, so those banks read CHR ROM there.
+pub struct Namco163
+{
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Chr,
+    internal_ram: [u8; INTERNAL_RAM_SIZE],
+    // $8000, $A000, $C000
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    nametable_banks: [u8; 4],
+    ram_port: u8,
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool
+}
+
+impl Namco163
+{
+    // A ROM without a full bank for the fixed $E000 window is refused.
+    pub fn new(cart: Cartridge, state: PowerOnState) -> Result<Self, CartridgeError>
+    {
+        if cart.prg_rom.len() < PRG_BANK_SIZE
+        {
+            return Err(CartridgeError::TooSmall { section: "PRG ROM", minimum: PRG_BANK_SIZE, found: cart.prg_rom.len() });
+        }
+
+        let mut prg_ram = vec![0; cart.prg_ram_size + cart.prg_nvram_size];
+        state.fill(&mut prg_ram);
+        let mut internal_ram = [0; INTERNAL_RAM_SIZE];
+        state.fill(&mut internal_ram);
+
+        // Start out with the header's mirroring until the game sets up
+        // its own nametables.
+        let nametable_banks = match cart.mirroring
+        {
+            Mirroring::Horizontal => [0xE0, 0xE0, 0xE1, 0xE1],
+            _ => [0xE0, 0xE1, 0xE0, 0xE1]
+        };
+
+        Ok(Namco163 {
+            prg_rom: cart.prg_rom,
+            prg_ram,
+            chr: Chr::new(cart.chr_rom, cart.chr_ram_size, state),
+            internal_ram,
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            nametable_banks,
+            ram_port: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false
+        })
+    }
+
+    fn prg_offset(&self, address: usize) -> usize
+    {
+        let offset = match address
+        {
+            0xE000..=0xFFFF => self.prg_rom.len() - PRG_BANK_SIZE + (address & 0x1FFF),
+            _ => self.prg_banks[(address - 0x8000) / PRG_BANK_SIZE] as usize * PRG_BANK_SIZE + (address & 0x1FFF)
+        };
+        offset % self.prg_rom.len()
+    }
+
+    fn prg_ram_writable(&self, address: usize) -> bool
+    {
+        let window = (address - 0x6000) / 0x0800;
+        !self.prg_ram.is_empty() && self.ram_port & WRITE_ENABLE_MASK == WRITE_ENABLE && self.ram_port & (1 << window) == 0
+    }
+
+    fn chr_offset(&self, address: usize) -> usize
+    {
+        let bank = match address
+        {
+            0x0000..=0x1FFF => self.chr_banks[address / CHR_BANK_SIZE],
+            _ => self.nametable_banks[(address >> 10) & 0x03]
+        };
+        bank as usize * CHR_BANK_SIZE + (address & 0x03FF)
+    }
+
+    fn internal_ram_address(&self) -> usize
+    {
+        (self.ram_port & RAM_ADDRESS) as usize
+    }
+
+    fn advance_ram_port(&mut self)
+    {
+        if self.ram_port & AUTO_INCREMENT != 0
+        {
+            let address = (self.ram_port.wrapping_add(1)) & RAM_ADDRESS;
+            self.ram_port = (self.ram_port & !RAM_ADDRESS) | address;
+        }
+    }
+}
+
+impl Mapper for Namco163
+{
+    fn cpu_read(&mut self, address: usize) -> Result<u8, MemError>
+    {
+        let data = self.cpu_peek(address);
+        if let 0x4800..=0x4FFF = address
+        {
+            self.advance_ram_port();
+        }
+        data
+    }
+
+    fn cpu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        match address
+        {
+            0x4800..=0x4FFF => {
+                self.internal_ram[self.internal_ram_address()] = data;
+                self.advance_ram_port();
+            },
+            0x5000..=0x57FF => {
+                self.irq_counter = (self.irq_counter & 0x7F00) | data as u16;
+                self.irq_pending = false;
+            },
+            0x5800..=0x5FFF => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | (((data & !IRQ_ENABLE) as u16) << 8);
+                self.irq_enabled = data & IRQ_ENABLE != 0;
+                self.irq_pending = false;
+            },
+            0x6000..=0x7FFF if self.prg_ram_writable(address) => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(address - 0x6000) % len] = data;
+            },
+            0x8000..=0xBFFF => self.chr_banks[(address - 0x8000) / 0x0800] = data,
+            0xC000..=0xDFFF => self.nametable_banks[(address - 0xC000) / 0x0800] = data,
+            0xE000..=0xE7FF => self.prg_banks[0] = data & 0x3F,
+            0xE800..=0xEFFF => self.prg_banks[1] = data & 0x3F,
+            0xF000..=0xF7FF => self.prg_banks[2] = data & 0x3F,
+            0xF800..=0xFFFF => self.ram_port = data,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn cpu_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        match address
+        {
+            0x4800..=0x4FFF => Ok(self.internal_ram[self.internal_ram_address()]),
+            0x5000..=0x57FF => Ok(self.irq_counter as u8),
+            0x5800..=0x5FFF => {
+                let enable = if self.irq_enabled { IRQ_ENABLE } else { 0 };
+                Ok((self.irq_counter >> 8) as u8 | enable)
+            },
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => Ok(self.prg_ram[(address - 0x6000) % self.prg_ram.len()]),
+            0x8000..=0xFFFF => Ok(self.prg_rom[self.prg_offset(address)]),
+            _ => Ok(0x00)
+        }
+    }
+
+    fn cpu_open_bus_mask(&self, address: usize) -> u8
+    {
+        match address
+        {
+            0x4800..=0x5FFF => 0x00,
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => 0x00,
+            0x8000..=0xFFFF => 0x00,
+            _ => 0xFF
+        }
+    }
+
+    fn ppu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        let offset = self.chr_offset(address);
+        self.chr.write(offset, data);
+        Ok(())
+    }
+
+    fn ppu_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        Ok(self.chr.read(self.chr_offset(address)))
+    }
+
+    fn mirroring(&self) -> Mirroring
+    {
+        match self.nametable_banks
+        {
+            [0xE0, 0xE0, 0xE1, 0xE1] => Mirroring::Horizontal,
+            [0xE0, 0xE1, 0xE0, 0xE1] => Mirroring::Vertical,
+            [0xE0, 0xE0, 0xE0, 0xE0] => Mirroring::SingleScreenA,
+            [0xE1, 0xE1, 0xE1, 0xE1] => Mirroring::SingleScreenB,
+            _ => Mirroring::FourScreen
+        }
+    }
+
+    fn nametable(&self, address: usize) -> Nametable
+    {
+        let bank = self.nametable_banks[(address >> 10) & 0x03];
+        if bank >= CIRAM_BANKS
+        {
+            Nametable::Ciram((bank & 1) as usize)
+        }
+        else
+        {
+            Nametable::Cartridge
+        }
+    }
+
+    // The counter counts up every CPU cycle and stops at $7FFF, which is
+    // when the IRQ fires.
+    fn notify_cpu_cycles(&mut self, cycles: u32)
+    {
+        if !self.irq_enabled || self.irq_counter >= IRQ_COUNTER_MAX
+        {
+            return;
+        }
+        let counter = (self.irq_counter as u32 + cycles).min(IRQ_COUNTER_MAX as u32);
+        self.irq_counter = counter as u16;
+        if self.irq_counter == IRQ_COUNTER_MAX
+        {
+            self.irq_pending = true;
+        }
+    }
+
+    fn irq(&self) -> bool
+    {
+        self.irq_pending
+    }
+
+    fn prg_ram(&mut self) -> &mut [u8]
+    {
+        &mut self.prg_ram
+    }
+
+    fn name(&self) -> &'static str
+    {
+        "Namco 163"
+    }
+}
+
+#[cfg(test)]
+mod namco163tests
+{
+    use crate::mapper::namco163::*;
+    use crate::mapper::test_rom;
+
+    fn cartridge() -> Cartridge
+    {
+        let mut cart = test_rom(19, PRG_BANK_SIZE, 16, CHR_BANK_SIZE, 128);
+        cart.mirroring = Mirroring::Vertical;
+        cart
+    }
+
+    fn setup() -> Namco163
+    {
+        Namco163::new(cartridge(), PowerOnState::Zeros).unwrap()
+    }
+
+    #[test]
+    fn rejects_prg_rom_smaller_than_fixed_bank()
+    {
+        let mut cart = cartridge();
+        cart.prg_rom.truncate(0x1000);
+
+        match Namco163::new(cart, PowerOnState::Zeros)
+        {
+            Err(e) => assert_eq!(CartridgeError::TooSmall { section: "PRG ROM", minimum: 0x2000, found: 0x1000 }, e),
+            Ok(_) => panic!("Namco 163 with 4 KiB PRG ROM was accepted")
+        }
+    }
+
+    #[test]
+    fn prg_and_chr_banks()
+    {
+        let mut m = setup();
+        m.cpu_write(0xE000, 3).unwrap();
+        m.cpu_write(0xE800, 4).unwrap();
+        m.cpu_write(0xF000, 5).unwrap();
+        m.cpu_write(0xB800, 99).unwrap();
+
+        assert_eq!(3, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(4, m.cpu_peek(0xA000).unwrap());
+        assert_eq!(5, m.cpu_peek(0xC000).unwrap());
+        assert_eq!(15, m.cpu_peek(0xE000).unwrap());
+        assert_eq!(99, m.ppu_peek(0x1C00).unwrap());
+    }
+
+    #[test]
+    fn nametables_from_ciram_or_chr_rom()
+    {
+        let mut m = setup();
+        assert_eq!(Mirroring::Vertical, m.mirroring());
+
+        m.cpu_write(0xC000, 0xE1).unwrap();
+        m.cpu_write(0xC800, 42).unwrap();
+
+        assert_eq!(Nametable::Ciram(1), m.nametable(0x2000));
+        assert_eq!(Nametable::Cartridge, m.nametable(0x2400));
+        assert_eq!(42, m.ppu_peek(0x2400).unwrap());
+        assert_eq!(42, m.ppu_peek(0x27FF).unwrap());
+    }
+
+    #[test]
+    fn prg_ram_write_protect()
+    {
+        let mut m = setup();
+        m.cpu_write(0x6000, 0x11).unwrap();
+        assert_eq!(0x00, m.cpu_peek(0x6000).unwrap());
+
+        // Only the second 2 KiB window is protected.
+        m.cpu_write(0xF800, WRITE_ENABLE | 0b0010).unwrap();
+        m.cpu_write(0x6000, 0x11).unwrap();
+        m.cpu_write(0x6800, 0x22).unwrap();
+
+        assert_eq!(0x11, m.cpu_peek(0x6000).unwrap());
+        assert_eq!(0x00, m.cpu_peek(0x6800).unwrap());
+    }
+
+    #[test]
+    fn internal_ram_port_auto_increments()
+    {
+        let mut m = setup();
+        m.cpu_write(0xF800, AUTO_INCREMENT | 0x7E).unwrap();
+        m.cpu_write(0x4800, 0xAA).unwrap();
+        m.cpu_write(0x4800, 0xBB).unwrap();
+        m.cpu_write(0x4800, 0xCC).unwrap();
+
+        assert_eq!(0xAA, m.internal_ram[0x7E]);
+        assert_eq!(0xBB, m.internal_ram[0x7F]);
+        assert_eq!(0xCC, m.internal_ram[0x00]);
+
+        m.cpu_write(0xF800, AUTO_INCREMENT | 0x7F).unwrap();
+        assert_eq!(0xBB, m.cpu_read(0x4800).unwrap());
+        assert_eq!(0xCC, m.cpu_read(0x4800).unwrap());
+    }
+
+    #[test]
+    fn irq_fires_at_7fff()
+    {
+        let mut m = setup();
+        m.cpu_write(0x5000, 0xF0).unwrap();
+        m.cpu_write(0x5800, IRQ_ENABLE | 0x7F).unwrap();
+
+        m.notify_cpu_cycles(0x0E);
+        assert!(!m.irq());
+        m.notify_cpu_cycles(0x10);
+        assert!(m.irq());
+        assert_eq!(0xFF, m.cpu_peek(0x5800).unwrap());
+        assert_eq!(0xFF, m.cpu_peek(0x5000).unwrap());
+
+        m.cpu_write(0x5000, 0x00).unwrap();
+        assert!(!m.irq());
+    }
+}
@@ -0,0 +1,175 @@
+use crate::memory::*;
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::mapper::{Chr, Mapper};
+
+// Mapper 0: no bank switching at all. NROM-256 has 32 KiB PRG ROM at
+// $8000-$FFFF, NROM-128 has 16 KiB that shows up at $8000 and again at
+// $C000, so both see the vectors at $FFFA-$FFFF. Family Basic adds
+// PRG RAM at $6000-$7FFF.
+pub struct Nrom
+{
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Chr,
+    mirroring: Mirroring
+}
+
+impl Nrom
+{
+    pub fn new(cart: Cartridge, state: PowerOnState) -> Self
+    {
+        let mut prg_ram = vec![0; cart.prg_ram_size + cart.prg_nvram_size];
+        state.fill(&mut prg_ram);
+
+        Nrom {
+            prg_rom: cart.prg_rom,
+            prg_ram,
+            chr: Chr::new(cart.chr_rom, cart.chr_ram_size, state),
+            mirroring: cart.mirroring
+        }
+    }
+
+    fn drives(&self, address: usize) -> bool
+    {
+        match address
+        {
+            0x6000..=0x7FFF => !self.prg_ram.is_empty(),
+            0x8000..=0xFFFF => !self.prg_rom.is_empty(),
+            _ => false
+        }
+    }
+}
+
+impl Mapper for Nrom
+{
+    fn cpu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        // There are no registers, writes to ROM are lost.
+        if let 0x6000..=0x7FFF = address
+        {
+            if !self.prg_ram.is_empty()
+            {
+                let len = self.prg_ram.len();
+                self.prg_ram[(address - 0x6000) % len] = data;
+            }
+        }
+        Ok(())
+    }
+
+    fn cpu_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        if !self.drives(address)
+        {
+            return Ok(0x00);
+        }
+
+        match address
+        {
+            0x6000..=0x7FFF => Ok(self.prg_ram[(address - 0x6000) % self.prg_ram.len()]),
+            _ => Ok(self.prg_rom[(address - 0x8000) % self.prg_rom.len()])
+        }
+    }
+
+    fn cpu_open_bus_mask(&self, address: usize) -> u8
+    {
+        if self.drives(address) { 0x00 } else { 0xFF }
+    }
+
+    fn ppu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        self.chr.write(address, data);
+        Ok(())
+    }
+
+    fn ppu_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        Ok(self.chr.read(address))
+    }
+
+    fn mirroring(&self) -> Mirroring
+    {
+        self.mirroring
+    }
+
+    fn prg_ram(&mut self) -> &mut [u8]
+    {
+        &mut self.prg_ram
+    }
+
+    fn name(&self) -> &'static str
+    {
+        "NROM"
+    }
+}
+
+#[cfg(test)]
+mod nromtests
+{
+    use crate::mapper::nrom::*;
+
+    fn cart(prg: Vec<u8>, chr: Vec<u8>) -> Cartridge
+    {
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, (prg.len() / 0x4000) as u8, (chr.len() / 0x2000) as u8, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        rom.extend(prg);
+        rom.extend(chr);
+        Cartridge::from_bytes(&rom).unwrap()
+    }
+
+    #[test]
+    fn nrom128_mirrors_prg_at_c000()
+    {
+        let mut prg = vec![0xEA; 0x4000];
+        prg[0x3FFC] = 0x00;
+        prg[0x3FFD] = 0x80;
+        let m = Nrom::new(cart(prg, vec![0; 0x2000]), PowerOnState::Zeros);
+
+        assert_eq!(0x00, m.cpu_peek(0xFFFC).unwrap());
+        assert_eq!(0x80, m.cpu_peek(0xFFFD).unwrap());
+        assert_eq!(0x80, m.cpu_peek(0xBFFD).unwrap());
+    }
+
+    #[test]
+    fn nrom256_maps_32k()
+    {
+        let mut prg = vec![0x00; 0x8000];
+        prg[0x0000] = 0x11;
+        prg[0x4000] = 0x22;
+        let m = Nrom::new(cart(prg, vec![0; 0x2000]), PowerOnState::Zeros);
+
+        assert_eq!(0x11, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(0x22, m.cpu_peek(0xC000).unwrap());
+    }
+
+    #[test]
+    fn writes_to_prg_rom_are_ignored()
+    {
+        let mut m = Nrom::new(cart(vec![0x33; 0x4000], vec![0; 0x2000]), PowerOnState::Zeros);
+
+        m.cpu_write(0x8000, 0x00).unwrap();
+
+        assert_eq!(0x33, m.cpu_read(0x8000).unwrap());
+    }
+
+    #[test]
+    fn prg_ram_at_6000()
+    {
+        let mut m = Nrom::new(cart(vec![0x00; 0x4000], vec![0; 0x2000]), PowerOnState::Zeros);
+
+        m.cpu_write(0x6123, 0x5A).unwrap();
+
+        assert_eq!(0x5A, m.cpu_read(0x6123).unwrap());
+        assert_eq!(0x00, m.cpu_open_bus_mask(0x6123));
+        assert_eq!(0xFF, m.cpu_open_bus_mask(0x5000));
+    }
+
+    #[test]
+    fn chr_and_mirroring_come_from_header()
+    {
+        let mut chr = vec![0x00; 0x2000];
+        chr[0x1000] = 0x7E;
+        let m = Nrom::new(cart(vec![0x00; 0x4000], chr), PowerOnState::Zeros);
+
+        assert_eq!(0x7E, m.ppu_peek(0x1000).unwrap());
+        assert_eq!(Mirroring::Vertical, m.mirroring());
+    }
+}
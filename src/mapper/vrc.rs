@@ -0,0 +1,511 @@
+use crate::memory::*;
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::mapper::{Chr, Mapper};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+// The IRQ prescaler divides CPU cycles down to scanlines: 341 dots per
+// line, 3 dots per CPU cycle.
+const PRESCALER_PERIOD: i16 = 341;
+const PRESCALER_STEP: i16 = 3;
+
+const IRQ_ENABLE_AFTER_ACK: u8 = 0b001;
+const IRQ_ENABLE: u8           = 0b010;
+const IRQ_CYCLE_MODE: u8       = 0b100;
+
+// Konami boards connect the chip's register select pins A0/A1 to different
+// CPU address lines. Each field is a mask of the CPU address lines that act
+// as the pin. iNES 1.0 files do not tell the variants apart, for those both
+// candidate lines are ORed, which works for all known games of a mapper
+// number.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Wiring
+{
+    pub a0: usize,
+    pub a1: usize
+}
+
+impl Wiring
+{
+    // $x000-$x003 for any CPU address that hits one of the chip's registers.
+    pub fn register(&self, address: usize) -> usize
+    {
+        let a0 = (address & self.a0 != 0) as usize;
+        let a1 = (address & self.a1 != 0) as usize;
+        (address & 0xF000) | (a1 << 1) | a0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Chip
+{
+    Vrc2,
+    // VRC2a ignores the low bit of its CHR bank registers.
+    Vrc2a,
+    Vrc4
+}
+
+// Picks chip and wiring for mappers 21, 22, 23 and 25. There is no table of
+// known ROMs, so an iNES 1.0 file (submapper 0) gets a VRC4 that listens on
+// both wirings of its mapper. For mapper 23 that covers VRC4f and VRC4e, and
+// since VRC2b uses the VRC4f lines its bank registers work too. What it gets
+// wrong on a VRC2b is the chip: it has no IRQ and no PRG swap mode, so such
+// files need submapper 3 to be emulated exactly.
+pub fn vrc24_variant(mapper: u16, submapper: u8) -> (Chip, Wiring)
+{
+    let wiring = |a0, a1| Wiring { a0, a1 };
+    match (mapper, submapper)
+    {
+        (21, 1) => (Chip::Vrc4, wiring(0x02, 0x04)),    // VRC4a
+        (21, 2) => (Chip::Vrc4, wiring(0x40, 0x80)),    // VRC4c
+        (21, _) => (Chip::Vrc4, wiring(0x42, 0x84)),
+        (22, _) => (Chip::Vrc2a, wiring(0x02, 0x01)),   // VRC2a
+        (23, 1) => (Chip::Vrc4, wiring(0x01, 0x02)),    // VRC4f
+        (23, 2) => (Chip::Vrc4, wiring(0x04, 0x08)),    // VRC4e
+        (23, 3) => (Chip::Vrc2, wiring(0x01, 0x02)),    // VRC2b
+        (23, _) => (Chip::Vrc4, wiring(0x05, 0x0A)),
+        (25, 1) => (Chip::Vrc4, wiring(0x02, 0x01)),    // VRC4b
+        (25, 2) => (Chip::Vrc4, wiring(0x08, 0x04)),    // VRC4d
+        (25, 3) => (Chip::Vrc2, wiring(0x02, 0x01)),    // VRC2c
+        (_, _) => (Chip::Vrc4, wiring(0x0A, 0x05))
+    }
+}
+
+// The IRQ counter of VRC4, VRC6 and VRC7. It counts up and fires when it
+// wraps from $FF, reloading from the latch. In scanline mode it is clocked
+// by a prescaler that approximates one scanline in CPU cycles, in cycle
+// mode by every CPU cycle.
+pub struct VrcIrq
+{
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    control: u8,
+    pending: bool
+}
+
+impl VrcIrq
+{
+    pub fn new() -> Self
+    {
+        VrcIrq { latch: 0, counter: 0, prescaler: PRESCALER_PERIOD, control: 0, pending: false }
+    }
+
+    pub fn write_latch(&mut self, data: u8)
+    {
+        self.latch = data;
+    }
+
+    // VRC4 loads the latch one nibble at a time.
+    pub fn write_latch_nibble(&mut self, high: bool, data: u8)
+    {
+        self.latch = if high { (self.latch & 0x0F) | (data << 4) } else { (self.latch & 0xF0) | (data & 0x0F) };
+    }
+
+    pub fn write_control(&mut self, data: u8)
+    {
+        self.control = data;
+        self.pending = false;
+        if data & IRQ_ENABLE != 0
+        {
+            self.counter = self.latch;
+            self.prescaler = PRESCALER_PERIOD;
+        }
+    }
+
+    pub fn acknowledge(&mut self)
+    {
+        self.pending = false;
+        let enable = if self.control & IRQ_ENABLE_AFTER_ACK != 0 { IRQ_ENABLE } else { 0 };
+        self.control = (self.control & !IRQ_ENABLE) | enable;
+    }
+
+    pub fn clock_cpu(&mut self, cycles: u32)
+    {
+        if self.control & IRQ_ENABLE == 0
+        {
+            return;
+        }
+
+        for _ in 0..cycles
+        {
+            if self.control & IRQ_CYCLE_MODE != 0
+            {
+                self.clock_counter();
+                continue;
+            }
+
+            self.prescaler -= PRESCALER_STEP;
+            if self.prescaler <= 0
+            {
+                self.prescaler += PRESCALER_PERIOD;
+                self.clock_counter();
+            }
+        }
+    }
+
+    fn clock_counter(&mut self)
+    {
+        if self.counter == 0xFF
+        {
+            self.counter = self.latch;
+            self.pending = true;
+        }
+        else
+        {
+            self.counter += 1;
+        }
+    }
+
+    pub fn pending(&self) -> bool
+    {
+        self.pending
+    }
+}
+
+// Mappers 21, 22, 23 and 25: VRC2 and VRC4. Registers after undoing the
+// wiring:
+//   $8000       PRG bank at $8000 (or $C000 in swapped mode)
+//   $9000-$9001 mirroring, $9002-$9003 PRG swap mode (VRC4)
+//   $A000       PRG bank at $A000
+//   $B000-$E003 CHR banks 0-7, low nibble at even, high nibble at odd
+//               registers
+//   $F000-$F003 IRQ latch low/high, control, acknowledge (VRC4)
+pub struct Vrc24
+{
+    chip: Chip,
+    wiring: Wiring,
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Chr,
+    prg_banks: [u8; 2],
+    prg_swap: bool,
+    chr_banks: [u16; 8],
+    mirroring: Mirroring,
+    irq: VrcIrq
+}
+
+impl Vrc24
+{
+    pub fn new(cart: Cartridge, state: PowerOnState) -> Self
+    {
+        let (chip, wiring) = vrc24_variant(cart.mapper, cart.submapper);
+        let mut prg_ram = vec![0; cart.prg_ram_size + cart.prg_nvram_size];
+        state.fill(&mut prg_ram);
+
+        Vrc24 {
+            chip,
+            wiring,
+            prg_rom: cart.prg_rom,
+            prg_ram,
+            chr: Chr::new(cart.chr_rom, cart.chr_ram_size, state),
+            prg_banks: [0, 0],
+            prg_swap: false,
+            chr_banks: [0; 8],
+            mirroring: cart.mirroring,
+            irq: VrcIrq::new()
+        }
+    }
+
+    fn prg_offset(&self, address: usize) -> usize
+    {
+        let banks = self.prg_rom.len() / PRG_BANK_SIZE;
+        let second_last = banks.saturating_sub(2);
+        let bank = match (address - 0x8000) / PRG_BANK_SIZE
+        {
+            0 => if self.prg_swap { second_last } else { self.prg_banks[0] as usize },
+            1 => self.prg_banks[1] as usize,
+            2 => if self.prg_swap { self.prg_banks[0] as usize } else { second_last },
+            _ => banks.saturating_sub(1)
+        };
+        (bank * PRG_BANK_SIZE + (address & 0x1FFF)) % self.prg_rom.len()
+    }
+
+    fn chr_offset(&self, address: usize) -> usize
+    {
+        let bank = self.chr_banks[address / CHR_BANK_SIZE] as usize;
+        let bank = if self.chip == Chip::Vrc2a { bank >> 1 } else { bank };
+        bank * CHR_BANK_SIZE + (address & 0x03FF)
+    }
+
+    fn write_register(&mut self, register: usize, data: u8)
+    {
+        let vrc4 = self.chip == Chip::Vrc4;
+        match register
+        {
+            0x8000..=0x8003 => self.prg_banks[0] = data & 0x1F,
+            0x9000..=0x9001 if vrc4 => {
+                self.mirroring = match data & 0x03
+                {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenA,
+                    _ => Mirroring::SingleScreenB
+                };
+            },
+            0x9002..=0x9003 if vrc4 => self.prg_swap = data & 0x02 != 0,
+            0x9000..=0x9003 => {
+                self.mirroring = if data & 1 == 0 { Mirroring::Vertical } else { Mirroring::Horizontal };
+            },
+            0xA000..=0xA003 => self.prg_banks[1] = data & 0x1F,
+            0xB000..=0xE003 => {
+                let index = ((register >> 12) - 0xB) * 2 + ((register >> 1) & 1);
+                let bank = self.chr_banks[index];
+                self.chr_banks[index] = if register & 1 == 0
+                {
+                    (bank & 0x1F0) | (data & 0x0F) as u16
+                }
+                else
+                {
+                    // VRC2 has four bits, VRC4 five.
+                    let high = if vrc4 { data & 0x1F } else { data & 0x0F };
+                    (bank & 0x0F) | ((high as u16) << 4)
+                };
+            },
+            0xF000 if vrc4 => self.irq.write_latch_nibble(false, data),
+            0xF001 if vrc4 => self.irq.write_latch_nibble(true, data),
+            0xF002 if vrc4 => self.irq.write_control(data),
+            0xF003 if vrc4 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for Vrc24
+{
+    fn cpu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        match address
+        {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(address - 0x6000) % len] = data;
+            },
+            0x8000..=0xFFFF => {
+                let register = self.wiring.register(address);
+                self.write_register(register, data);
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn cpu_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        match address
+        {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => Ok(self.prg_ram[(address - 0x6000) % self.prg_ram.len()]),
+            0x8000..=0xFFFF => Ok(self.prg_rom[self.prg_offset(address)]),
+            _ => Ok(0x00)
+        }
+    }
+
+    fn cpu_open_bus_mask(&self, address: usize) -> u8
+    {
+        match address
+        {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => 0x00,
+            0x8000..=0xFFFF => 0x00,
+            _ => 0xFF
+        }
+    }
+
+    fn ppu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        let offset = self.chr_offset(address);
+        self.chr.write(offset, data);
+        Ok(())
+    }
+
+    fn ppu_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        Ok(self.chr.read(self.chr_offset(address)))
+    }
+
+    fn mirroring(&self) -> Mirroring
+    {
+        self.mirroring
+    }
+
+    fn notify_cpu_cycles(&mut self, cycles: u32)
+    {
+        self.irq.clock_cpu(cycles);
+    }
+
+    fn irq(&self) -> bool
+    {
+        self.irq.pending()
+    }
+
+    fn prg_ram(&mut self) -> &mut [u8]
+    {
+        &mut self.prg_ram
+    }
+
+    fn name(&self) -> &'static str
+    {
+        match self.chip
+        {
+            Chip::Vrc4 => "VRC4",
+            _ => "VRC2"
+        }
+    }
+}
+
+#[cfg(test)]
+mod vrctests
+{
+    use crate::mapper::vrc::*;
+    use crate::mapper::test_rom;
+
+    fn cart(mapper: u8, submapper: u8, prg_16k: usize, chr_8k: usize) -> Cartridge
+    {
+        let mut cart = test_rom(mapper, PRG_BANK_SIZE, prg_16k * 2, CHR_BANK_SIZE, chr_8k * 8);
+        cart.submapper = submapper;
+        cart
+    }
+
+    #[test]
+    fn wiring_maps_address_lines_to_registers()
+    {
+        let vrc4c = Wiring { a0: 0x40, a1: 0x80 };
+        assert_eq!(0xB003, vrc4c.register(0xB0C0));
+        assert_eq!(0xB001, vrc4c.register(0xB040));
+
+        let vrc4b = Wiring { a0: 0x02, a1: 0x01 };
+        assert_eq!(0x9002, vrc4b.register(0x9001));
+        assert_eq!(0x9001, vrc4b.register(0x9002));
+    }
+
+    #[test]
+    fn submapper_selects_variant()
+    {
+        assert_eq!((Chip::Vrc4, Wiring { a0: 0x02, a1: 0x04 }), vrc24_variant(21, 1));
+        assert_eq!((Chip::Vrc2, Wiring { a0: 0x01, a1: 0x02 }), vrc24_variant(23, 3));
+        assert_eq!((Chip::Vrc4, Wiring { a0: 0x08, a1: 0x04 }), vrc24_variant(25, 2));
+        assert_eq!((Chip::Vrc4, Wiring { a0: 0x0A, a1: 0x05 }), vrc24_variant(25, 0));
+    }
+
+    #[test]
+    fn mapper_23_without_submapper_runs_vrc2b_banking()
+    {
+        assert_eq!((Chip::Vrc4, Wiring { a0: 0x05, a1: 0x0A }), vrc24_variant(23, 0));
+
+        // VRC2b: A0, A1
+        let mut m = Vrc24::new(cart(23, 0, 2, 4), PowerOnState::Zeros);
+        m.cpu_write(0xB000, 0x05).unwrap();
+        m.cpu_write(0xB001, 0x01).unwrap();
+        m.cpu_write(0xB002, 0x0F).unwrap();
+        m.cpu_write(0xB003, 0x01).unwrap();
+
+        assert_eq!(0x15, m.ppu_peek(0x0000).unwrap());
+        assert_eq!(0x1F, m.ppu_peek(0x0400).unwrap());
+    }
+
+    #[test]
+    fn vrc4_prg_banks_and_swap_mode()
+    {
+        // VRC4f: A0, A1
+        let mut m = Vrc24::new(cart(23, 1, 8, 1), PowerOnState::Zeros);
+        m.cpu_write(0x8000, 3).unwrap();
+        m.cpu_write(0xA000, 5).unwrap();
+
+        assert_eq!(3, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(5, m.cpu_peek(0xA000).unwrap());
+        assert_eq!(14, m.cpu_peek(0xC000).unwrap());
+        assert_eq!(15, m.cpu_peek(0xE000).unwrap());
+
+        m.cpu_write(0x9002, 0x02).unwrap();
+        assert_eq!(14, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(3, m.cpu_peek(0xC000).unwrap());
+    }
+
+    #[test]
+    fn vrc4_chr_banks_from_nibbles()
+    {
+        // VRC4e: A2, A3
+        let mut m = Vrc24::new(cart(23, 2, 2, 4), PowerOnState::Zeros);
+        m.cpu_write(0xB000, 0x05).unwrap();
+        m.cpu_write(0xB004, 0x01).unwrap();
+        m.cpu_write(0xE008, 0x0F).unwrap();
+        m.cpu_write(0xE00C, 0x01).unwrap();
+
+        assert_eq!(0x15, m.ppu_peek(0x0000).unwrap());
+        assert_eq!(0x1F, m.ppu_peek(0x1C00).unwrap());
+    }
+
+    #[test]
+    fn vrc2a_ignores_low_chr_bit()
+    {
+        let mut m = Vrc24::new(cart(22, 0, 2, 4), PowerOnState::Zeros);
+        // VRC2a: A1 is the chip's A0
+        m.cpu_write(0xB000, 0x07).unwrap();
+
+        assert_eq!(0x03, m.ppu_peek(0x0000).unwrap());
+    }
+
+    #[test]
+    fn vrc4_mirroring()
+    {
+        let mut m = Vrc24::new(cart(21, 1, 2, 1), PowerOnState::Zeros);
+        m.cpu_write(0x9000, 3).unwrap();
+        assert_eq!(Mirroring::SingleScreenB, m.mirroring());
+        m.cpu_write(0x9000, 1).unwrap();
+        assert_eq!(Mirroring::Horizontal, m.mirroring());
+    }
+
+    #[test]
+    fn vrc2_has_no_irq()
+    {
+        let mut m = Vrc24::new(cart(23, 3, 2, 1), PowerOnState::Zeros);
+        m.cpu_write(0xF002, IRQ_ENABLE | IRQ_CYCLE_MODE).unwrap();
+        m.notify_cpu_cycles(1000);
+
+        assert!(!m.irq());
+    }
+
+    #[test]
+    fn irq_cycle_mode_fires_on_wrap()
+    {
+        // VRC4a: A1, A2
+        let mut m = Vrc24::new(cart(21, 1, 2, 1), PowerOnState::Zeros);
+        m.cpu_write(0xF000, 0x0C).unwrap();
+        m.cpu_write(0xF002, 0x0F).unwrap();
+        m.cpu_write(0xF004, IRQ_ENABLE | IRQ_CYCLE_MODE).unwrap();
+
+        m.notify_cpu_cycles(0xFF - 0xFC);
+        assert!(!m.irq());
+        m.notify_cpu_cycles(1);
+        assert!(m.irq());
+
+        m.cpu_write(0xF006, 0).unwrap();
+        assert!(!m.irq());
+    }
+
+    #[test]
+    fn irq_scanline_mode_uses_prescaler()
+    {
+        let mut irq = VrcIrq::new();
+        irq.write_latch(0xFE);
+        irq.write_control(IRQ_ENABLE);
+
+        // Two scanlines of 113 2/3 cycles.
+        irq.clock_cpu(227);
+        assert!(!irq.pending());
+        irq.clock_cpu(1);
+        assert!(irq.pending());
+    }
+
+    #[test]
+    fn acknowledge_restores_enable_after_ack()
+    {
+        let mut irq = VrcIrq::new();
+        irq.write_latch(0xFF);
+        irq.write_control(IRQ_ENABLE);
+        irq.acknowledge();
+
+        irq.clock_cpu(1000);
+        assert!(!irq.pending());
+    }
+}
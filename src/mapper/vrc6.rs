@@ -0,0 +1,289 @@
+use crate::memory::*;
+use crate::cartridge::{Cartridge, CartridgeError, Mirroring};
+use crate::mapper::{Chr, Mapper};
+use crate::mapper::vrc::{VrcIrq, Wiring};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+const PPU_MODE: u8       = 0b00000011;
+const PPU_MIRRORING: u8  = 0b00001100;
+const PRG_RAM_ENABLE: u8 = 0b10000000;
+
+// Mappers 24 (VRC6a) and 26 (VRC6b, A0 and A1 swapped). Registers after
+// undoing the wiring:
+//   $8000-$8003 16 KiB PRG bank at $8000
+//   $9000-$B002 expansion sound, not emulated
+//   $B003       PPU banking mode, mirroring, PRG RAM enable
+//   $C000-$C003 8 KiB PRG bank at $C000, $E000 is fixed to the last bank
+//   $D000-$E003 CHR registers R0-R7
+//   $F000-$F002 IRQ latch, control, acknowledge
+pub struct Vrc6
+{
+    wiring: Wiring,
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Chr,
+    prg_16k: u8,
+    prg_8k: u8,
+    chr_banks: [u8; 8],
+    ppu_control: u8,
+    irq: VrcIrq
+}
+
+impl Vrc6
+{
+    // $E000 is fixed to the last 8 KiB of PRG ROM, so there has to be one.
+    pub fn new(cart: Cartridge, state: PowerOnState) -> Result<Self, CartridgeError>
+    {
+        if cart.prg_rom.len() < PRG_BANK_SIZE
+        {
+            return Err(CartridgeError::TooSmall { section: "PRG ROM", minimum: PRG_BANK_SIZE, found: cart.prg_rom.len() });
+        }
+
+        let wiring = if cart.mapper == 26 { Wiring { a0: 0x02, a1: 0x01 } } else { Wiring { a0: 0x01, a1: 0x02 } };
+        let mut prg_ram = vec![0; cart.prg_ram_size + cart.prg_nvram_size];
+        state.fill(&mut prg_ram);
+
+        Ok(Vrc6 {
+            wiring,
+            prg_rom: cart.prg_rom,
+            prg_ram,
+            chr: Chr::new(cart.chr_rom, cart.chr_ram_size, state),
+            prg_16k: 0,
+            prg_8k: 0,
+            chr_banks: [0; 8],
+            ppu_control: 0,
+            irq: VrcIrq::new()
+        })
+    }
+
+    fn prg_ram_enabled(&self) -> bool
+    {
+        !self.prg_ram.is_empty() && self.ppu_control & PRG_RAM_ENABLE != 0
+    }
+
+    fn prg_offset(&self, address: usize) -> usize
+    {
+        let offset = match address
+        {
+            0x8000..=0xBFFF => self.prg_16k as usize * 2 * PRG_BANK_SIZE + (address & 0x3FFF),
+            0xC000..=0xDFFF => self.prg_8k as usize * PRG_BANK_SIZE + (address & 0x1FFF),
+            _ => self.prg_rom.len() - PRG_BANK_SIZE + (address & 0x1FFF)
+        };
+        offset % self.prg_rom.len()
+    }
+
+    // Mode 0: eight 1 KiB banks, mode 1: four 2 KiB banks from R0-R3,
+    // modes 2 and 3: R0-R3 as 1 KiB banks at $0000, R4 and R5 as 2 KiB
+    // banks at $1000.
+    fn chr_offset(&self, address: usize) -> usize
+    {
+        let slot = address / CHR_BANK_SIZE;
+        let bank = match self.ppu_control & PPU_MODE
+        {
+            0 => self.chr_banks[slot] as usize,
+            1 => ((self.chr_banks[slot / 2] as usize) << 1) | (slot & 1),
+            _ if slot < 4 => self.chr_banks[slot] as usize,
+            _ => ((self.chr_banks[4 + (slot - 4) / 2] as usize) << 1) | (slot & 1)
+        };
+        bank * CHR_BANK_SIZE + (address & 0x03FF)
+    }
+
+    fn write_register(&mut self, register: usize, data: u8)
+    {
+        match register
+        {
+            0x8000..=0x8003 => self.prg_16k = data & 0x0F,
+            0xB003 => self.ppu_control = data,
+            0xC000..=0xC003 => self.prg_8k = data & 0x1F,
+            0xD000..=0xE003 => {
+                let index = ((register >> 12) - 0xD) * 4 + (register & 0x03);
+                self.chr_banks[index] = data;
+            },
+            0xF000 => self.irq.write_latch(data),
+            0xF001 => self.irq.write_control(data),
+            0xF002 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for Vrc6
+{
+    fn cpu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        match address
+        {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(address - 0x6000) % len] = data;
+            },
+            0x8000..=0xFFFF => {
+                let register = self.wiring.register(address);
+                self.write_register(register, data);
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn cpu_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        match address
+        {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => Ok(self.prg_ram[(address - 0x6000) % self.prg_ram.len()]),
+            0x8000..=0xFFFF => Ok(self.prg_rom[self.prg_offset(address)]),
+            _ => Ok(0x00)
+        }
+    }
+
+    fn cpu_open_bus_mask(&self, address: usize) -> u8
+    {
+        match address
+        {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => 0x00,
+            0x8000..=0xFFFF => 0x00,
+            _ => 0xFF
+        }
+    }
+
+    fn ppu_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        let offset = self.chr_offset(address);
+        self.chr.write(offset, data);
+        Ok(())
+    }
+
+    fn ppu_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        Ok(self.chr.read(self.chr_offset(address)))
+    }
+
+    fn mirroring(&self) -> Mirroring
+    {
+        match (self.ppu_control & PPU_MIRRORING) >> 2
+        {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenA,
+            _ => Mirroring::SingleScreenB
+        }
+    }
+
+    fn notify_cpu_cycles(&mut self, cycles: u32)
+    {
+        self.irq.clock_cpu(cycles);
+    }
+
+    fn irq(&self) -> bool
+    {
+        self.irq.pending()
+    }
+
+    fn prg_ram(&mut self) -> &mut [u8]
+    {
+        &mut self.prg_ram
+    }
+
+    fn name(&self) -> &'static str
+    {
+        "VRC6"
+    }
+}
+
+#[cfg(test)]
+mod vrc6tests
+{
+    use crate::mapper::vrc6::*;
+    use crate::mapper::test_rom;
+
+    fn cartridge(mapper: u8) -> Cartridge
+    {
+        test_rom(mapper, PRG_BANK_SIZE, 16, CHR_BANK_SIZE, 32)
+    }
+
+    fn setup(mapper: u8) -> Vrc6
+    {
+        Vrc6::new(cartridge(mapper), PowerOnState::Zeros).unwrap()
+    }
+
+    #[test]
+    fn rejects_prg_rom_smaller_than_fixed_bank()
+    {
+        let mut cart = cartridge(24);
+        cart.prg_rom.truncate(0x1000);
+
+        match Vrc6::new(cart, PowerOnState::Zeros)
+        {
+            Err(e) => assert_eq!(CartridgeError::TooSmall { section: "PRG ROM", minimum: 0x2000, found: 0x1000 }, e),
+            Ok(_) => panic!("VRC6 with 4 KiB PRG ROM was accepted")
+        }
+    }
+
+    #[test]
+    fn prg_banks()
+    {
+        let mut m = setup(24);
+        m.cpu_write(0x8000, 3).unwrap();
+        m.cpu_write(0xC000, 9).unwrap();
+
+        assert_eq!(6, m.cpu_peek(0x8000).unwrap());
+        assert_eq!(7, m.cpu_peek(0xA000).unwrap());
+        assert_eq!(9, m.cpu_peek(0xC000).unwrap());
+        assert_eq!(15, m.cpu_peek(0xE000).unwrap());
+    }
+
+    #[test]
+    fn vrc6b_swaps_a0_and_a1()
+    {
+        let mut a = setup(24);
+        let mut b = setup(26);
+        a.cpu_write(0xD001, 5).unwrap();
+        b.cpu_write(0xD002, 5).unwrap();
+
+        assert_eq!(5, a.ppu_peek(0x0400).unwrap());
+        assert_eq!(5, b.ppu_peek(0x0400).unwrap());
+    }
+
+    #[test]
+    fn chr_mode_1_uses_2k_banks()
+    {
+        let mut m = setup(24);
+        m.cpu_write(0xB003, 0x01).unwrap();
+        m.cpu_write(0xD001, 3).unwrap();
+
+        assert_eq!(6, m.ppu_peek(0x0800).unwrap());
+        assert_eq!(7, m.ppu_peek(0x0C00).unwrap());
+    }
+
+    #[test]
+    fn b003_sets_mirroring_and_prg_ram()
+    {
+        let mut m = setup(24);
+        m.cpu_write(0x6000, 0x12).unwrap();
+        assert_eq!(0xFF, m.cpu_open_bus_mask(0x6000));
+
+        m.cpu_write(0xB003, PRG_RAM_ENABLE | 0b0100).unwrap();
+        m.cpu_write(0x6000, 0x12).unwrap();
+
+        assert_eq!(0x12, m.cpu_peek(0x6000).unwrap());
+        assert_eq!(Mirroring::Horizontal, m.mirroring());
+    }
+
+    #[test]
+    fn irq_is_clocked_by_cpu_cycles()
+    {
+        let mut m = setup(24);
+        m.cpu_write(0xF000, 0xFE).unwrap();
+        m.cpu_write(0xF001, 0x06).unwrap();
+
+        m.notify_cpu_cycles(1);
+        assert!(!m.irq());
+        m.notify_cpu_cycles(1);
+        assert!(m.irq());
+
+        m.cpu_write(0xF002, 0).unwrap();
+        assert!(!m.irq());
+    }
+}
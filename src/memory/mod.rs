@@ -1,43 +1,292 @@
+use crate::log;
+use std::sync::{Arc, Mutex};
 
-#[derive(Debug, PartialEq)]
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessKind
+{
+    Read,
+    Write
+}
+
+// Everything that can go wrong on a memory access. The address is the one
+// the caller used: devices report their local address and the bus
+// translates it back into a CPU address before passing the error on.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MemError
 {
-    Ok,
-    BadAddress
+    // The device is mapped, but has nothing at this address.
+    BadAddress { address: usize, access: AccessKind, device: &'static str },
+    // Write to memory that can only be read.
+    ReadOnly { address: usize, access: AccessKind, device: &'static str },
+    // No device is mapped at this address.
+    Unmapped { address: usize, access: AccessKind }
+}
+
+impl MemError
+{
+    pub fn with_address(self, new_address: usize) -> Self
+    {
+        let mut res = self;
+        match &mut res
+        {
+            MemError::BadAddress { address, .. } |
+            MemError::ReadOnly { address, .. } |
+            MemError::Unmapped { address, .. } => *address = new_address
+        }
+        res
+    }
+}
+
+impl std::fmt::Display for MemError
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        match self
+        {
+            MemError::BadAddress { address, access, device } =>
+                write!(f, "{}.{:?}: {:#06x} -> Bad Addr", device, access, address),
+            MemError::ReadOnly { address, access, device } =>
+                write!(f, "{}.{:?}: {:#06x} -> Read only", device, access, address),
+            MemError::Unmapped { address, access } =>
+                write!(f, "Memory.{:?}: {:#06x} -> Unmapped", access, address)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MapError
+{
+    InvalidRange { begin: usize, end: usize },
+    Overlap { begin: usize, end: usize, existing_begin: usize, existing_end: usize }
 }
 
+// Interrupt lines a device can report through MemTickResult::IRQ.
+pub const NMI_LINE: u8 = 0b001;
+pub const IRQ_LINE: u8 = 0b010;
+pub const RES_LINE: u8 = 0b100;
+
+// Returned by tick: IRQ(lines) means the device holds these lines asserted
+// right now, Ok means it has released (acknowledged) all of them.
 pub enum MemTickResult
 {
     Ok,
     IRQ(u8)
 }
 
+// Aggregates the interrupt lines of all devices on the bus. Every device
+// owns one slot and is the only one to set or clear it. IRQ is level
+// triggered and stays asserted while any device holds it, NMI is edge
+// triggered and only fires once when the combined line goes high.
+pub struct InterruptController
+{
+    lines: Vec<u8>,
+    nmi_level: bool,
+    nmi_pending: bool
+}
+
+impl InterruptController
+{
+    pub fn new() -> Self
+    {
+        InterruptController { lines: vec!(), nmi_level: false, nmi_pending: false }
+    }
+
+    pub fn set_lines(&mut self, source: usize, lines: u8)
+    {
+        if self.lines.len() <= source
+        {
+            self.lines.resize(source + 1, 0);
+        }
+        self.lines[source] = lines;
+
+        let nmi_level = self.combined() & NMI_LINE != 0;
+        if nmi_level && !self.nmi_level
+        {
+            self.nmi_pending = true;
+        }
+        self.nmi_level = nmi_level;
+    }
+
+    pub fn acknowledge(&mut self, source: usize)
+    {
+        self.set_lines(source, 0);
+    }
+
+    fn combined(&self) -> u8
+    {
+        self.lines.iter().fold(0, |acc, x| acc | x)
+    }
+
+    // Lines the CPU has to look at: a latched NMI edge (consumed by this
+    // call) plus the current IRQ and reset levels.
+    pub fn take_pending(&mut self) -> u8
+    {
+        let mut res = self.combined() & !NMI_LINE;
+        if self.nmi_pending
+        {
+            res |= NMI_LINE;
+            self.nmi_pending = false;
+        }
+        res
+    }
+}
+
+// Bus mastering operations a device can ask for. The bus carries them out
+// right after the access (or tick) that triggered them, so the device never
+// has to reach back into the memory map it is part of.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BusRequest
+{
+    // Copy the page $xx00-$xxFF to OAM via $2004.
+    OamDma(u8)
+}
+
 pub trait Memory
 {
     fn read_byte(&mut self, address: usize) -> Result<u8, MemError>;
-    fn write_byte(&mut self, address: usize, data: u8) -> MemError;
+    fn write_byte(&mut self, address: usize, data: u8) -> Result<(), MemError>;
+
+    // Opcode fetch, a plain read for everything but the bus itself.
+    fn read_opcode(&mut self, address: usize) -> Result<u8, MemError>
+    {
+        self.read_byte(address)
+    }
+
     fn read_u16(&mut self, address: usize) -> Result<u16, MemError>
     {
-        let hi = self.read_byte(address);
-        let lo = self.read_byte(address + 1);
+        let lo = self.read_byte(address)?;
+        let hi = self.read_byte(address + 1)?;
+        Ok((lo as u16) | ((hi as u16) << 8))
+    }
+
+    // Side effect free access for debuggers, tracers and the disassembler:
+    // a peek must never change device state (e.g. clear the VBlank flag or
+    // advance a read buffer), a poke changes the stored value only and does
+    // not trigger whatever a regular write would set in motion.
+    #[allow(dead_code)] // For debuggers, the emulator itself only peeks in tests.
+    fn peek(&self, address: usize) -> Result<u8, MemError>;
+    #[allow(dead_code)]
+    fn poke(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        self.write_byte(address, data)
+    }
+
+    #[allow(dead_code)]
+    fn peek_u16(&self, address: usize) -> Result<u16, MemError>
+    {
+        let lo = self.peek(address)?;
+        let hi = self.peek(address + 1)?;
+        Ok((lo as u16) | ((hi as u16) << 8))
+    }
+
+    // Bits of the value at address that the device does not drive, e.g. the
+    // upper bits of the controller ports. The bus fills these from its open
+    // bus latch.
+    fn open_bus_mask(&self, _address: usize) -> u8
+    {
+        0x00
+    }
+
+    // How the CPU should treat accesses that fail, a bus returns the policy
+    // it was configured with.
+    fn unmapped_policy(&self) -> UnmappedPolicy
+    {
+        UnmappedPolicy::Break
+    }
+
+    fn take_bus_request(&mut self) -> Option<BusRequest>
+    {
+        None
+    }
+
+    // Target of an OAM DMA, only the PPU implements this.
+    fn write_oam_dma(&mut self, _data: &[u8; 256])
+    {
+    }
+
+    // Cycles the CPU has to sit out because the bus was busy with DMA since
+    // the last call. odd_cycle tells whether the CPU is on an odd cycle,
+    // which costs an extra alignment cycle.
+    fn take_stall_cycles(&mut self, _odd_cycle: bool) -> u32
+    {
+        0
+    }
+
+    fn tick(&mut self, _clock_ticks: u32) -> MemTickResult;
+
+    fn name(&self) -> &'static str
+    {
+        "<unnamed>"
+    }
+}
+
+// What RAM contains when the console is switched on. Real RAM comes up in
+// a semi random state, which some games (and many homebrew bugs) depend on.
+// Random carries its seed, so a run can be repeated with the same contents.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerOnState
+{
+    Zeros,
+    Ones,
+    // $00 $00 $00 $00 $FF $FF $FF $FF ...
+    Alternating,
+    Random(u64)
+}
+
+impl PowerOnState
+{
+    // Random contents with a fresh seed taken from the clock.
+    pub fn random() -> Self
+    {
+        let seed = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_nanos() as u64)
+                        .unwrap_or(0);
+        PowerOnState::Random(seed)
+    }
+
+    // Accepts "zeros", "ones", "alternating", "random" or a seed for a
+    // reproducible random state.
+    pub fn parse(text: &str) -> Option<Self>
+    {
+        match text
+        {
+            "zeros" => Some(PowerOnState::Zeros),
+            "ones" => Some(PowerOnState::Ones),
+            "alternating" => Some(PowerOnState::Alternating),
+            "random" => Some(PowerOnState::random()),
+            seed => seed.parse().ok().map(PowerOnState::Random)
+        }
+    }
 
-        match hi
+    pub fn fill(&self, data: &mut [u8])
+    {
+        match *self
         {
-            Ok(v) => {
-                match lo 
+            PowerOnState::Zeros => data.iter_mut().for_each(|x| *x = 0x00),
+            PowerOnState::Ones => data.iter_mut().for_each(|x| *x = 0xFF),
+            PowerOnState::Alternating => {
+                for (i, x) in data.iter_mut().enumerate()
                 {
-                    Ok(v2) => Ok((v as u16) | ((v2 as u16) << 8) ),
-                    _ => Err(MemError::BadAddress)
-                }            
+                    *x = if i & 0x04 == 0 { 0x00 } else { 0xFF };
+                }
             },
-            _ => Err(MemError::BadAddress)
+            PowerOnState::Random(seed) => {
+                // splitmix64, good enough and needs no extra crate.
+                let mut state = seed;
+                for chunk in data.chunks_mut(8)
+                {
+                    state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+                    let mut z = state;
+                    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+                    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+                    z ^= z >> 31;
+                    chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+                }
+            }
         }
-
-        //let res = lo | (hi << 8);
-        //res
     }
-
-    fn tick(&mut self, _clock_ticks: u32) -> MemTickResult;
 }
 
 pub struct RawMemory
@@ -51,66 +300,284 @@ struct AddressRange
     end: usize
 }
 
+impl AddressRange
+{
+    fn contains(&self, address: usize) -> bool
+    {
+        self.begin <= address && self.end >= address
+    }
+
+    fn covers_page(&self, page: usize) -> bool
+    {
+        self.begin <= page * PAGE_SIZE && self.end >= (page + 1) * PAGE_SIZE - 1
+    }
+
+    fn overlaps(&self, other: &AddressRange) -> bool
+    {
+        self.begin <= other.end && self.end >= other.begin
+    }
+
+    fn touches_page(&self, page: usize) -> bool
+    {
+        self.begin < (page + 1) * PAGE_SIZE && self.end >= page * PAGE_SIZE
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookKind
+{
+    Read,
+    Write,
+    Exec
+}
+
+// Gets the bus address and the value read, written or fetched. Returning
+// Some(x) replaces the value with x, e.g. for cheats.
+pub type HookFn = Box<dyn FnMut(usize, u8) -> Option<u8>>;
+
+struct Hook
+{
+    kind: HookKind,
+    range: AddressRange,
+    callback: HookFn
+}
+
+// Tooling (cheats, RAM watch, tracers, ...) attaches here instead of
+// wrapping devices. Hooks run in the order they were added, each one sees
+// the value as substituted by the ones before.
+pub struct HookRegistry
+{
+    hooks: Vec<Hook>
+}
+
+impl HookRegistry
+{
+    pub fn new() -> Self
+    {
+        HookRegistry { hooks: vec!() }
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.hooks.is_empty()
+    }
+
+    fn add(&mut self, range: AddressRange, kind: HookKind, callback: HookFn)
+    {
+        self.hooks.push(Hook { kind, range, callback });
+    }
+
+    fn run(&mut self, kind: HookKind, address: usize, value: u8) -> u8
+    {
+        let mut value = value;
+        for h in self.hooks.iter_mut().filter(|x| x.kind == kind && x.range.contains(address))
+        {
+            if let Some(x) = (h.callback)(address, value)
+            {
+                value = x;
+            }
+        }
+        value
+    }
+}
+
 pub struct CompositeMemoryEntry
 {
     range: AddressRange,
+    mirror_size: usize,
+    priority: u8,
     handler: Box<dyn Memory>
 }
 
+impl CompositeMemoryEntry
+{
+    // Folds a bus address into the handler's own address space. Ranges
+    // that are not mirrored use a mirror size equal to their length.
+    fn local_address(&self, address: usize) -> usize
+    {
+        (address - self.range.begin) % self.mirror_size
+    }
+}
+
+const PAGE_SIZE: usize = 0x100;
+const PAGE_COUNT: usize = 0x100;
+
+// Each 256 byte page of the CPU address space either has no handler at all,
+// belongs completely to a single (highest priority) handler or is shared by
+// several smaller or prioritized ranges, e.g. the $4000 page with APU, IO
+// and DMA registers. Only the last case needs a search and that is limited
+// to the handlers touching the page.
+#[derive(Clone)]
+enum PageEntry
+{
+    Unmapped,
+    Single(usize),
+    Shared(Vec<usize>)
+}
+
+// What happens on an access to an address no device responds to. Reads
+// return the open bus value unless the policy is Break, in which case the
+// access fails so the CPU stops right at the offending instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnmappedPolicy
+{
+    Silent,
+    Log,
+    Break
+}
+
+impl UnmappedPolicy
+{
+    // Accepts "silent", "log" or "break".
+    pub fn parse(text: &str) -> Option<Self>
+    {
+        match text
+        {
+            "silent" => Some(UnmappedPolicy::Silent),
+            "log" => Some(UnmappedPolicy::Log),
+            "break" => Some(UnmappedPolicy::Break),
+            _ => None
+        }
+    }
+}
+
 pub struct CompositeMemory
 {
-    handlers: Vec< Box<CompositeMemoryEntry> >
+    handlers: Vec<CompositeMemoryEntry>,
+    pages: Vec<PageEntry>,
+    // Last value seen on the data bus, returned by reads nobody answers.
+    open_bus: u8,
+    unmapped_policy: UnmappedPolicy,
+    logger: Option<Arc<Mutex<log::logger>>>,
+    oam_dma_done: bool,
+    interrupts: InterruptController,
+    hooks: HookRegistry
 }
 
 impl Memory for CompositeMemory
 {
     fn read_byte(&mut self, address: usize) -> Result<u8, MemError>
     {
-        // find correct handler:
-        let mut it = self.handlers.iter_mut();
+        let value = self.read_from_device(address)?;
+        if self.hooks.is_empty()
+        {
+            return Ok(value);
+        }
 
-        let m = it.find(|x| x.range.begin <= address && x.range.end >= address);
+        self.open_bus = self.hooks.run(HookKind::Read, address, value);
+        Ok(self.open_bus)
+    }
 
-        if let Some(m) =  m {
-            let rangestart = m.range.begin;
-            return m.handler.read_byte(address - rangestart);
+    fn read_opcode(&mut self, address: usize) -> Result<u8, MemError>
+    {
+        let value = self.read_byte(address)?;
+        if self.hooks.is_empty()
+        {
+            return Ok(value);
         }
-        Err(MemError::BadAddress)    
+        Ok(self.hooks.run(HookKind::Exec, address, value))
     }
 
-    fn write_byte(&mut self, address: usize, data: u8) -> MemError
+    fn write_byte(&mut self, address: usize, data: u8) -> Result<(), MemError>
     {
-        // find correct handler:
-        let mut it = self.handlers.iter_mut();
+        let data = if self.hooks.is_empty() { data } else { self.hooks.run(HookKind::Write, address, data) };
 
-        let m = it.find(|x| x.range.begin <= address && x.range.end >= address);
+        // The CPU drives the data bus on writes, no matter who listens.
+        self.open_bus = data;
 
-        if let Some(m) = m {
+        if let Some(idx) = self.find_handler(address)
+        {
+            let m = &mut self.handlers[idx];
             //let err = format!("          {:#4x} -> {:#2x}", address, data);
             //println!("{}", err);
-            return m.handler.write_byte(address - m.range.begin, data);
+            let local = m.local_address(address);
+            let res = m.handler.write_byte(local, data).map_err(|e| e.with_address(address));
+            self.service_bus_request(idx);
+            return res;
+        }
+
+        self.unmapped_access(MemError::Unmapped { address, access: AccessKind::Write })
+    }
+
+    fn peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        match self.find_handler(address)
+        {
+            Some(idx) => {
+                let m = &self.handlers[idx];
+                let local = m.local_address(address);
+                let undriven = m.handler.open_bus_mask(local);
+                let value = m.handler.peek(local).map_err(|e| e.with_address(address))?;
+                Ok((value & !undriven) | (self.open_bus & undriven))
+            },
+            None if self.unmapped_policy == UnmappedPolicy::Break => Err(MemError::Unmapped { address, access: AccessKind::Read }),
+            None => Ok(self.open_bus)
+        }
+    }
+
+    fn poke(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        match self.find_handler(address)
+        {
+            Some(idx) => {
+                let m = &mut self.handlers[idx];
+                let local = m.local_address(address);
+                m.handler.poke(local, data).map_err(|e| e.with_address(address))
+            },
+            None => Err(MemError::Unmapped { address, access: AccessKind::Write })
+        }
+    }
+
+    fn unmapped_policy(&self) -> UnmappedPolicy
+    {
+        self.unmapped_policy
+    }
+
+    fn take_stall_cycles(&mut self, odd_cycle: bool) -> u32
+    {
+        if !self.oam_dma_done
+        {
+            return 0;
         }
+        self.oam_dma_done = false;
 
-        let err = format!("Memory.WriteByte: {:#4x} -> Bad Addr", address);
-        println!("{}", err);
-        MemError::BadAddress
+        // One dummy cycle, one more if we have to wait for an even cycle and
+        // 256 read/write pairs.
+        if odd_cycle { 514 } else { 513 }
     }
 
     fn tick(&mut self, clock_ticks: u32) -> MemTickResult
     {
+        // Every device gets its tick, no matter who raised an interrupt.
         let it = self.handlers.iter_mut();
-        let mut result = MemTickResult::Ok;
-        for m in it
+        let mut requesters = vec!();
+        for (idx, m) in it.enumerate()
         {
-            let res = m.handler.tick(clock_ticks);
-            match result
+            match m.handler.tick(clock_ticks)
             {
-                MemTickResult::Ok => result = res,
-                MemTickResult::IRQ(_) => break
+                MemTickResult::Ok => self.interrupts.acknowledge(idx),
+                MemTickResult::IRQ(lines) => self.interrupts.set_lines(idx, lines)
             }
+            if let Some(req) = m.handler.take_bus_request()
+            {
+                requesters.push(req);
+            }
+        }
+        for req in requesters
+        {
+            self.perform_bus_request(req);
+        }
 
+        match self.interrupts.take_pending()
+        {
+            0 => MemTickResult::Ok,
+            lines => MemTickResult::IRQ(lines)
         }
-        result
+    }
+
+    fn name(&self) -> &'static str
+    {
+        "CompositeMemory"
     }
 }
 
@@ -118,17 +585,249 @@ impl CompositeMemory
 {
     pub fn new() -> Self
     {
-        CompositeMemory {handlers: vec!()}
+        CompositeMemory {
+            handlers: vec!(),
+            pages: vec![PageEntry::Unmapped; PAGE_COUNT],
+            open_bus: 0x00,
+            unmapped_policy: UnmappedPolicy::Break,
+            logger: None,
+            oam_dma_done: false,
+            interrupts: InterruptController::new(),
+            hooks: HookRegistry::new()
+        }
+    }
+
+    // Hooks see every CPU access in begin..=end of the given kind (but not
+    // peek/poke). Exec hooks run on opcode fetches, in addition to read
+    // hooks.
+    pub fn add_hook(&mut self, begin: usize, end: usize, kind: HookKind, callback: HookFn)
+    {
+        self.hooks.add(AddressRange { begin, end }, kind, callback);
+    }
+
+    fn read_from_device(&mut self, address: usize) -> Result<u8, MemError>
+    {
+        if let Some(idx) = self.find_handler(address)
+        {
+            let m = &mut self.handlers[idx];
+            let local = m.local_address(address);
+            let undriven = m.handler.open_bus_mask(local);
+            let value = m.handler.read_byte(local).map_err(|e| e.with_address(address))?;
+            self.open_bus = (value & !undriven) | (self.open_bus & undriven);
+            return Ok(self.open_bus);
+        }
+
+        self.unmapped_access(MemError::Unmapped { address, access: AccessKind::Read })?;
+        Ok(self.open_bus)
+    }
+
+    pub fn set_unmapped_policy(&mut self, policy: UnmappedPolicy)
+    {
+        self.unmapped_policy = policy;
+    }
+
+    pub fn set_logger(&mut self, logger: Arc<Mutex<log::logger>>)
+    {
+        self.logger = Some(logger);
+    }
+
+    #[cfg(test)]
+    pub fn open_bus(&self) -> u8
+    {
+        self.open_bus
+    }
+
+    fn service_bus_request(&mut self, idx: usize)
+    {
+        if let Some(req) = self.handlers[idx].handler.take_bus_request()
+        {
+            self.perform_bus_request(req);
+        }
+    }
+
+    fn perform_bus_request(&mut self, req: BusRequest)
+    {
+        match req
+        {
+            BusRequest::OamDma(page) => {
+                let source = (page as usize) << 8;
+                let mut data = [0u8; 256];
+                for (i, value) in data.iter_mut().enumerate()
+                {
+                    *value = self.read_byte(source + i).unwrap_or(self.open_bus);
+                }
+
+                // The data goes straight into OAM, not through $2004.
+                if let Some(ppu) = self.find_handler(0x2004)
+                {
+                    self.handlers[ppu].handler.write_oam_dma(&data);
+                }
+                self.oam_dma_done = true;
+            }
+        }
+    }
+
+    // Applies the unmapped policy to err, only Break lets the access fail.
+    fn unmapped_access(&self, err: MemError) -> Result<(), MemError>
+    {
+        match self.unmapped_policy
+        {
+            UnmappedPolicy::Silent => Ok(()),
+            UnmappedPolicy::Log => {
+                self.log(format!("{}", err));
+                Ok(())
+            },
+            UnmappedPolicy::Break => {
+                self.log(format!("{}", err));
+                Err(err)
+            }
+        }
+    }
+
+    fn log(&self, message: String)
+    {
+        if let Some(logger) = &self.logger
+        {
+            logger.lock().unwrap().write(message);
+        }
+    }
+
+    pub fn register_range(&mut self, begin: usize, end: usize, mem:  Box<dyn Memory>) -> Result<(), MapError>
+    {
+        let size = if end >= begin { end - begin + 1 } else { 1 };
+        self.insert(begin, end, size, 0, mem)
+    }
+
+    // Maps a device of mirror_size bytes repeatedly into begin..=end, e.g.
+    // the 2 KiB internal RAM into $0000-$1FFF or the 8 PPU registers into
+    // $2000-$3FFF. The handler only ever sees addresses below mirror_size.
+    pub fn register_mirrored_range(&mut self, begin: usize, end: usize, mirror_size: usize, mem:  Box<dyn Memory>) -> Result<(), MapError>
+    {
+        self.insert(begin, end, mirror_size, 0, mem)
     }
 
-    pub fn register_range(&mut self, begin: usize, end: usize, mem:  Box<dyn Memory>)
+    // Ranges registered with register_range have priority 0 and must not
+    // overlap. A range with a different priority may be placed on top of
+    // (or below) existing ranges, the highest priority wins the lookup.
+    // Nothing in the console overlays ranges yet.
+    #[cfg(test)]
+    pub fn register_range_with_priority(&mut self, begin: usize, end: usize, priority: u8, mem:  Box<dyn Memory>) -> Result<(), MapError>
     {
+        let size = if end >= begin { end - begin + 1 } else { 1 };
+        self.insert(begin, end, size, priority, mem)
+    }
+
+    fn insert(&mut self, begin: usize, end: usize, mirror_size: usize, priority: u8, mem: Box<dyn Memory>) -> Result<(), MapError>
+    {
+        if begin > end || end >= PAGE_COUNT * PAGE_SIZE || mirror_size == 0
+        {
+            return Err(MapError::InvalidRange { begin, end });
+        }
+
+        let range = AddressRange{begin, end};
+        let clash = self.handlers.iter()
+                                 .find(|x| x.priority == priority && x.range.overlaps(&range));
+        if let Some(existing) = clash
+        {
+            return Err(MapError::Overlap { begin, end, existing_begin: existing.range.begin, existing_end: existing.range.end });
+        }
+
         let entry = CompositeMemoryEntry {
-            range : AddressRange{begin: begin, end: end},
+            range,
+            mirror_size,
+            priority,
             handler: mem
         };
 
-        self.handlers.push( Box::new(entry) );
+        self.handlers.push(entry);
+
+        for page in begin / PAGE_SIZE..=end / PAGE_SIZE
+        {
+            self.rebuild_page(page);
+        }
+        Ok(())
+    }
+
+    fn rebuild_page(&mut self, page: usize)
+    {
+        // Candidates are ordered by descending priority, so the first
+        // candidate containing an address is the one that wins the lookup.
+        let mut candidates: Vec<usize> = (0..self.handlers.len())
+            .filter(|&i| self.handlers[i].range.touches_page(page))
+            .collect();
+        candidates.sort_by_key(|&i| std::cmp::Reverse(self.handlers[i].priority));
+
+        self.pages[page] = match candidates.first()
+        {
+            None => PageEntry::Unmapped,
+            Some(&first) if self.handlers[first].range.covers_page(page) => PageEntry::Single(first),
+            Some(_) => PageEntry::Shared(candidates)
+        };
+    }
+
+    fn find_handler(&self, address: usize) -> Option<usize>
+    {
+        let page = address / PAGE_SIZE;
+        if page >= PAGE_COUNT
+        {
+            return None;
+        }
+
+        match &self.pages[page]
+        {
+            PageEntry::Unmapped => None,
+            PageEntry::Single(idx) => Some(*idx),
+            PageEntry::Shared(candidates) => candidates.iter()
+                                                       .copied()
+                                                       .find(|&i| self.handlers[i].range.contains(address))
+        }
+    }
+
+    #[cfg(test)]
+    fn find_handler_linear(&self, address: usize) -> Option<usize>
+    {
+        self.handlers.iter()
+                     .enumerate()
+                     .filter(|(_, x)| x.range.contains(address))
+                     .max_by_key(|(i, x)| (x.priority, std::cmp::Reverse(*i)))
+                     .map(|(i, _)| i)
+    }
+
+    // Lists every range of the address space from $0000 to $FFFF together
+    // with the device that handles it, including unmapped gaps.
+    pub fn describe_map(&self) -> String
+    {
+        let mut order: Vec<usize> = (0..self.handlers.len()).collect();
+        order.sort_by_key(|&i| (self.handlers[i].range.begin, std::cmp::Reverse(self.handlers[i].priority)));
+
+        let mut res = String::new();
+        let mut next_free = 0;
+        for i in order
+        {
+            let m = &self.handlers[i];
+            if m.range.begin > next_free
+            {
+                res += &format!("${:04X}-${:04X}  <unmapped>\n", next_free, m.range.begin - 1);
+            }
+
+            res += &format!("${:04X}-${:04X}  {}", m.range.begin, m.range.end, m.handler.name());
+            if m.mirror_size < m.range.end - m.range.begin + 1
+            {
+                res += &format!(", mirrored every ${:04X}", m.mirror_size);
+            }
+            if m.priority != 0
+            {
+                res += &format!(", priority {}", m.priority);
+            }
+            res += "\n";
+            next_free = next_free.max(m.range.end + 1);
+        }
+
+        if next_free < PAGE_COUNT * PAGE_SIZE
+        {
+            res += &format!("${:04X}-$FFFF  <unmapped>\n", next_free);
+        }
+        res
     }
 }
 
@@ -140,26 +839,31 @@ impl Memory for RawMemory
         {
             return Ok(self.data[address]);
         }        
-        let err = format!("RawMemory.Read: {:#04x} -> Bad Addr", address);
-        println!("{}", err);
-        Err(MemError::BadAddress)
+        Err(MemError::BadAddress { address, access: AccessKind::Read, device: self.name() })
     }
 
-    fn write_byte(&mut self, address: usize, data: u8) -> MemError
+    fn write_byte(&mut self, address: usize, data: u8) -> Result<(), MemError>
     {
         if address < self.data.len()
         {
             self.data[address] = data;
-            return MemError::Ok;
+            return Ok(());
         }
-        let err = format!("RawMemory.Write: {:#04x} -> Bad Addr", address);
-        println!("{}", err);
-        MemError::BadAddress
+        Err(MemError::BadAddress { address, access: AccessKind::Write, device: self.name() })
+    }
+
+    fn peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        self.data.get(address).copied().ok_or(MemError::BadAddress { address, access: AccessKind::Read, device: self.name() })
     }
 
     fn tick(&mut self, _clock_ticks: u32) -> MemTickResult {
         MemTickResult::Ok
     }
+
+    fn name(&self) -> &'static str {
+        "RawMemory"
+    }
 }
 
 impl RawMemory
@@ -181,6 +885,13 @@ impl RawMemory
         mem.fill(0x00);
         mem
     }
+
+    pub fn with_power_on(size: usize, state: PowerOnState) -> Self
+    {
+        let mut mem = RawMemory::new(size);
+        state.fill(&mut mem.data);
+        mem
+    }
 }
 
 #[test]
@@ -188,7 +899,7 @@ fn rawmem_can_write()
 {
     let mut m = RawMemory::new(0x8000);
 
-    m.write_byte(0x4004, 0xFF);
+    m.write_byte(0x4004, 0xFF).unwrap();
 
     let read = m.read_byte(0x4004).unwrap();
 
@@ -203,7 +914,51 @@ fn rawmem_write_out_of_bounds_fails_gracefully()
 
     let res = m.write_byte(0x4004, 0xFF);
 
-    assert_eq!(MemError::BadAddress, res);
+    assert_eq!(Err(MemError::BadAddress { address: 0x4004, access: AccessKind::Write, device: "RawMemory" }), res);
+}
+
+#[test]
+fn rawmem_power_on_patterns()
+{
+    let mut zeros = RawMemory::with_power_on(0x10, PowerOnState::Zeros);
+    let mut ones = RawMemory::with_power_on(0x10, PowerOnState::Ones);
+    let mut alternating = RawMemory::with_power_on(0x10, PowerOnState::Alternating);
+
+    assert_eq!(0x00, zeros.read_byte(0x0F).unwrap());
+    assert_eq!(0xFF, ones.read_byte(0x0F).unwrap());
+    let pattern: Vec<u8> = (0..0x10).map(|i| alternating.read_byte(i).unwrap()).collect();
+    assert_eq!(vec![0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF], pattern);
+}
+
+#[test]
+fn power_on_state_parses_config_values()
+{
+    assert_eq!(Some(PowerOnState::Zeros), PowerOnState::parse("zeros"));
+    assert_eq!(Some(PowerOnState::Ones), PowerOnState::parse("ones"));
+    assert_eq!(Some(PowerOnState::Alternating), PowerOnState::parse("alternating"));
+    assert_eq!(Some(PowerOnState::Random(42)), PowerOnState::parse("42"));
+    assert_eq!(None, PowerOnState::parse("garbage"));
+}
+
+#[test]
+fn unmapped_policy_parses_config_values()
+{
+    assert_eq!(Some(UnmappedPolicy::Silent), UnmappedPolicy::parse("silent"));
+    assert_eq!(Some(UnmappedPolicy::Log), UnmappedPolicy::parse("log"));
+    assert_eq!(Some(UnmappedPolicy::Break), UnmappedPolicy::parse("break"));
+    assert_eq!(None, UnmappedPolicy::parse("Log"));
+}
+
+#[test]
+fn rawmem_random_power_on_is_reproducible()
+{
+    let a = RawMemory::with_power_on(0x0803, PowerOnState::Random(1234));
+    let b = RawMemory::with_power_on(0x0803, PowerOnState::Random(1234));
+    let c = RawMemory::with_power_on(0x0803, PowerOnState::Random(4321));
+
+    assert_eq!(a.data, b.data);
+    assert_ne!(a.data, c.data);
+    assert!(a.data.iter().any(|&x| x != a.data[0]));
 }
 
 #[test]
@@ -212,8 +967,8 @@ fn compositemem_dispatches_memory_correctly()
     let mut m =  CompositeMemory::new();
     let r = RawMemory::new(0x4000);
 
-    m.register_range(0x1000, 0x5000, Box::new(r));
-    m.write_byte(0x1000, 0xFA);
+    m.register_range(0x1000, 0x5000, Box::new(r)).unwrap();
+    m.write_byte(0x1000, 0xFA).unwrap();
     let read = m.read_byte(0x1000).unwrap();
 
     assert_eq!(0xFA, read );
@@ -225,7 +980,419 @@ fn compositemem_write_to_invalid_range_fails_gracefully()
     let mut m =  CompositeMemory::new();
     let r = RawMemory::new(0x4000);
 
-    m.register_range(0x1000, 0x5000, Box::new(r));
+    m.register_range(0x1000, 0x5000, Box::new(r)).unwrap();
     let res = m.write_byte(0x21, 0xFA);
-    assert_eq!(MemError::BadAddress, res);
-}
\ No newline at end of file
+    assert_eq!(Err(MemError::Unmapped { address: 0x21, access: AccessKind::Write }), res);
+}
+
+#[test]
+fn compositemem_reports_device_errors_with_bus_address()
+{
+    let mut m =  CompositeMemory::new();
+    m.register_range(0x1000, 0x10FF, Box::new(RawMemory::new(0x10))).unwrap();
+
+    assert_eq!(Err(MemError::BadAddress { address: 0x1020, access: AccessKind::Write, device: "RawMemory" }),
+               m.write_byte(0x1020, 0x00));
+    assert_eq!(Err(MemError::BadAddress { address: 0x1020, access: AccessKind::Read, device: "RawMemory" }),
+               m.read_byte(0x1020));
+}
+
+#[test]
+fn compositemem_rejects_overlapping_ranges()
+{
+    let mut m =  CompositeMemory::new();
+
+    m.register_range(0x2000, 0x20FF, Box::new(RawMemory::new(0x100))).unwrap();
+    let res = m.register_range(0x2010, 0x2010, Box::new(RawMemory::new(0x01)));
+
+    assert_eq!(Err(MapError::Overlap { begin: 0x2010, end: 0x2010, existing_begin: 0x2000, existing_end: 0x20FF }), res);
+}
+
+#[test]
+fn compositemem_rejects_invalid_ranges()
+{
+    let mut m =  CompositeMemory::new();
+
+    assert_eq!(Err(MapError::InvalidRange { begin: 0x8000, end: 0x10000 }),
+               m.register_range(0x8000, 0x10000, Box::new(RawMemory::new(0x8001))));
+    assert_eq!(Err(MapError::InvalidRange { begin: 0x2001, end: 0x2000 }),
+               m.register_range(0x2001, 0x2000, Box::new(RawMemory::new(0x01))));
+}
+
+#[test]
+fn compositemem_higher_priority_wins_on_overlap()
+{
+    let mut m =  CompositeMemory::new();
+    let mut low = RawMemory::new(0x100);
+    let mut high = RawMemory::new(0x100);
+    low.write_byte(0x10, 0x11).unwrap();
+    low.write_byte(0x11, 0x33).unwrap();
+    high.write_byte(0x00, 0x22).unwrap();
+
+    m.register_range_with_priority(0x2010, 0x2010, 1, Box::new(high)).unwrap();
+    m.register_range(0x2000, 0x20FF, Box::new(low)).unwrap();
+
+    assert_eq!(0x22, m.read_byte(0x2010).unwrap());
+    assert_eq!(0x33, m.read_byte(0x2011).unwrap());
+}
+
+#[test]
+fn compositemem_describes_map()
+{
+    let mut m =  CompositeMemory::new();
+    m.register_mirrored_range(0x0000, 0x1FFF, 0x0800, Box::new(RawMemory::new(0x0800))).unwrap();
+    m.register_range(0x8000, 0xFFFF, Box::new(RawMemory::new(0x8000))).unwrap();
+    m.register_range_with_priority(0x8000, 0x8000, 2, Box::new(RawMemory::new(0x01))).unwrap();
+
+    let expected = "$0000-$1FFF  RawMemory, mirrored every $0800\n\
+                    $2000-$7FFF  <unmapped>\n\
+                    $8000-$8000  RawMemory, priority 2\n\
+                    $8000-$FFFF  RawMemory\n";
+    assert_eq!(expected, m.describe_map());
+}
+
+#[test]
+fn compositemem_dispatches_small_ranges_within_a_page()
+{
+    let mut m =  CompositeMemory::new();
+    let mut ppu_like = RawMemory::new(0x08);
+    let mut dma_like = RawMemory::new(0x01);
+    ppu_like.write_byte(0x02, 0xAA).unwrap();
+    dma_like.write_byte(0x00, 0xBB).unwrap();
+
+    m.register_range(0x2000, 0x2007, Box::new(ppu_like)).unwrap();
+    m.register_range(0x4014, 0x4014, Box::new(dma_like)).unwrap();
+
+    assert_eq!(0xAA, m.read_byte(0x2002).unwrap());
+    assert_eq!(0xBB, m.read_byte(0x4014).unwrap());
+    assert!(m.read_byte(0x2008).is_err());
+    assert!(m.read_byte(0x4013).is_err());
+    assert!(m.read_byte(0x4015).is_err());
+}
+
+#[test]
+fn compositemem_partial_page_falls_through_to_later_range()
+{
+    let mut m =  CompositeMemory::new();
+    let mut low = RawMemory::new(0x80);
+    let mut high = RawMemory::new(0x1000);
+    low.write_byte(0x7F, 0x01).unwrap();
+    high.write_byte(0x80, 0x02).unwrap();
+
+    m.register_range_with_priority(0x6000, 0x607F, 1, Box::new(low)).unwrap();
+    m.register_range(0x6000, 0x6FFF, Box::new(high)).unwrap();
+
+    assert_eq!(0x01, m.read_byte(0x607F).unwrap());
+    assert_eq!(0x02, m.read_byte(0x6080).unwrap());
+}
+
+#[test]
+fn compositemem_reads_u16_at_end_of_address_space()
+{
+    let mut m =  CompositeMemory::new();
+    m.register_range(0x8000, 0xFFFF, Box::new(RawMemory::new(0x8000))).unwrap();
+
+    m.write_byte(0xFFFE, 0x34).unwrap();
+    m.write_byte(0xFFFF, 0x12).unwrap();
+
+    assert_eq!(0x1234, m.read_u16(0xFFFE).unwrap());
+    assert!(m.read_u16(0xFFFF).is_err());
+}
+
+#[test]
+fn compositemem_mirrors_internal_ram()
+{
+    let mut m =  CompositeMemory::new();
+    m.register_mirrored_range(0x0000, 0x1FFF, 0x0800, Box::new(RawMemory::new(0x0800))).unwrap();
+
+    m.write_byte(0x0012, 0x5A).unwrap();
+    assert_eq!(0x5A, m.read_byte(0x0812).unwrap());
+    assert_eq!(0x5A, m.read_byte(0x1012).unwrap());
+    assert_eq!(0x5A, m.read_byte(0x1812).unwrap());
+
+    m.write_byte(0x1FFF, 0xA5).unwrap();
+    assert_eq!(0xA5, m.read_byte(0x07FF).unwrap());
+}
+
+#[test]
+fn compositemem_mirrors_ppu_registers()
+{
+    let mut m =  CompositeMemory::new();
+    m.register_mirrored_range(0x2000, 0x3FFF, 0x0008, Box::new(RawMemory::new(0x0008))).unwrap();
+
+    m.write_byte(0x3FFE, 0x21).unwrap();
+    assert_eq!(0x21, m.read_byte(0x2006).unwrap());
+    assert_eq!(0x21, m.read_byte(0x200E).unwrap());
+
+    m.write_byte(0x2008, 0x80).unwrap();
+    assert_eq!(0x80, m.read_byte(0x2000).unwrap());
+    assert!(m.read_byte(0x4000).is_err());
+}
+
+#[test]
+fn compositemem_peek_and_poke_are_dispatched_to_handlers()
+{
+    let mut m =  CompositeMemory::new();
+    m.register_mirrored_range(0x0000, 0x1FFF, 0x0800, Box::new(RawMemory::new(0x0800))).unwrap();
+
+    assert_eq!(Ok(()), m.poke(0x0810, 0x34));
+    m.poke(0x0011, 0x12).unwrap();
+
+    assert_eq!(0x34, m.peek(0x0010).unwrap());
+    assert_eq!(0x1234, m.peek_u16(0x1010).unwrap());
+    assert_eq!(Err(MemError::Unmapped { address: 0x2000, access: AccessKind::Write }), m.poke(0x2000, 0x00));
+    assert!(m.peek(0x2000).is_err());
+}
+
+#[cfg(test)]
+struct PartiallyDecodedPort;
+
+#[cfg(test)]
+impl Memory for PartiallyDecodedPort
+{
+    fn read_byte(&mut self, _address: usize) -> Result<u8, MemError> { Ok(0x01) }
+    fn write_byte(&mut self, _address: usize, _data: u8) -> Result<(), MemError> { Ok(()) }
+    fn peek(&self, _address: usize) -> Result<u8, MemError> { Ok(0x01) }
+    fn open_bus_mask(&self, _address: usize) -> u8 { 0xE0 }
+    fn tick(&mut self, _clock_ticks: u32) -> MemTickResult { MemTickResult::Ok }
+}
+
+#[test]
+fn compositemem_unmapped_read_returns_open_bus()
+{
+    let mut m =  CompositeMemory::new();
+    m.set_unmapped_policy(UnmappedPolicy::Silent);
+    m.register_range(0x0000, 0x07FF, Box::new(RawMemory::new(0x0800))).unwrap();
+    m.write_byte(0x0010, 0x5C).unwrap();
+
+    m.read_byte(0x0010).unwrap();
+    assert_eq!(0x5C, m.read_byte(0x5000).unwrap());
+    assert_eq!(0x5C, m.peek(0x5000).unwrap());
+
+    assert_eq!(Ok(()), m.write_byte(0x5000, 0x77));
+    assert_eq!(0x77, m.read_byte(0x5001).unwrap());
+}
+
+#[test]
+fn compositemem_partially_decoded_read_keeps_upper_open_bus_bits()
+{
+    let mut m =  CompositeMemory::new();
+    m.register_range(0x4016, 0x4016, Box::new(PartiallyDecodedPort)).unwrap();
+    m.register_range(0x0000, 0x07FF, Box::new(RawMemory::new(0x0800))).unwrap();
+    m.write_byte(0x0000, 0x40).unwrap();
+
+    assert_eq!(0x41, m.read_byte(0x4016).unwrap());
+    assert_eq!(0x41, m.open_bus());
+}
+
+#[test]
+fn compositemem_unmapped_access_logs_when_asked()
+{
+    let logger = Arc::new(Mutex::new(log::logger::new()));
+    let mut m =  CompositeMemory::new();
+    m.set_unmapped_policy(UnmappedPolicy::Log);
+    m.set_logger(logger.clone());
+
+    assert!(m.read_byte(0x5000).is_ok());
+    assert_eq!(Ok(()), m.write_byte(0x5000, 0x01));
+    assert_eq!(2, logger.lock().unwrap().len());
+}
+
+#[test]
+fn compositemem_unmapped_access_breaks_by_default()
+{
+    let mut m =  CompositeMemory::new();
+
+    assert!(m.read_byte(0x5000).is_err());
+    assert!(m.peek(0x5000).is_err());
+}
+
+#[test]
+fn compositemem_read_hook_substitutes_value()
+{
+    let mut m =  CompositeMemory::new();
+    m.register_mirrored_range(0x0000, 0x1FFF, 0x0800, Box::new(RawMemory::new(0x0800))).unwrap();
+    m.write_byte(0x0075, 0x01).unwrap();
+
+    m.add_hook(0x0075, 0x0075, HookKind::Read, Box::new(|_, _| Some(0x09)));
+
+    assert_eq!(0x09, m.read_byte(0x0075).unwrap());
+    assert_eq!(0x01, m.peek(0x0075).unwrap());
+    assert_eq!(0x00, m.read_byte(0x0076).unwrap());
+}
+
+#[test]
+fn compositemem_write_hook_observes_and_overrides()
+{
+    let seen = std::rc::Rc::new(std::cell::RefCell::new(vec!()));
+    let log = seen.clone();
+    let mut m =  CompositeMemory::new();
+    m.register_mirrored_range(0x0000, 0x1FFF, 0x0800, Box::new(RawMemory::new(0x0800))).unwrap();
+
+    m.add_hook(0x0000, 0x07FF, HookKind::Write, Box::new(move |adr, val| { log.borrow_mut().push((adr, val)); None }));
+    m.add_hook(0x0010, 0x0010, HookKind::Write, Box::new(|_, _| Some(0x63)));
+    m.write_byte(0x0010, 0x02).unwrap();
+    m.write_byte(0x0811, 0x03).unwrap();
+
+    assert_eq!(vec![(0x0010, 0x02)], *seen.borrow());
+    assert_eq!(0x63, m.read_byte(0x0010).unwrap());
+}
+
+#[test]
+fn compositemem_exec_hook_runs_on_opcode_fetch_only()
+{
+    let fetches = std::rc::Rc::new(std::cell::Cell::new(0));
+    let counter = fetches.clone();
+    let mut m =  CompositeMemory::new();
+    let mut rom = RawMemory::new(0x8000);
+    rom.write_byte(0x0000, 0xEA).unwrap();
+    m.register_range(0x8000, 0xFFFF, Box::new(rom)).unwrap();
+
+    m.add_hook(0x8000, 0x8000, HookKind::Exec, Box::new(move |_, _| { counter.set(counter.get() + 1); None }));
+    m.read_byte(0x8000).unwrap();
+    assert_eq!(0xEA, m.read_opcode(0x8000).unwrap());
+    assert_eq!(1, fetches.get());
+}
+
+#[cfg(test)]
+struct InterruptSource
+{
+    lines: std::rc::Rc<std::cell::Cell<u8>>,
+    ticks: std::rc::Rc<std::cell::Cell<u32>>
+}
+
+#[cfg(test)]
+impl Memory for InterruptSource
+{
+    fn read_byte(&mut self, _address: usize) -> Result<u8, MemError> { Ok(0) }
+    fn write_byte(&mut self, _address: usize, _data: u8) -> Result<(), MemError> { Ok(()) }
+    fn peek(&self, _address: usize) -> Result<u8, MemError> { Ok(0) }
+    fn tick(&mut self, clock_ticks: u32) -> MemTickResult
+    {
+        self.ticks.set(self.ticks.get() + clock_ticks);
+        match self.lines.get()
+        {
+            0 => MemTickResult::Ok,
+            x => MemTickResult::IRQ(x)
+        }
+    }
+}
+
+// Lines to report and ticks seen by an InterruptSource.
+#[cfg(test)]
+type InterruptProbe = (std::rc::Rc<std::cell::Cell<u8>>, std::rc::Rc<std::cell::Cell<u32>>);
+
+#[cfg(test)]
+fn interrupt_sources(m: &mut CompositeMemory, count: usize) -> Vec<InterruptProbe>
+{
+    let mut res = vec!();
+    for i in 0..count
+    {
+        let lines = std::rc::Rc::new(std::cell::Cell::new(0));
+        let ticks = std::rc::Rc::new(std::cell::Cell::new(0));
+        let src = InterruptSource { lines: lines.clone(), ticks: ticks.clone() };
+        m.register_range(0x5000 + i, 0x5000 + i, Box::new(src)).unwrap();
+        res.push((lines, ticks));
+    }
+    res
+}
+
+#[cfg(test)]
+fn tick_lines(m: &mut CompositeMemory) -> u8
+{
+    match m.tick(1)
+    {
+        MemTickResult::Ok => 0,
+        MemTickResult::IRQ(x) => x
+    }
+}
+
+#[test]
+fn compositemem_ticks_all_devices_when_one_raises_irq()
+{
+    let mut m =  CompositeMemory::new();
+    let src = interrupt_sources(&mut m, 3);
+    src[0].0.set(IRQ_LINE);
+
+    assert_eq!(IRQ_LINE, tick_lines(&mut m));
+    assert!(src.iter().all(|(_, ticks)| ticks.get() == 1));
+}
+
+#[test]
+fn compositemem_irq_stays_asserted_until_every_device_released_it()
+{
+    let mut m =  CompositeMemory::new();
+    let src = interrupt_sources(&mut m, 2);
+    src[0].0.set(IRQ_LINE);
+    src[1].0.set(IRQ_LINE);
+    assert_eq!(IRQ_LINE, tick_lines(&mut m));
+
+    src[0].0.set(0);
+    assert_eq!(IRQ_LINE, tick_lines(&mut m));
+
+    src[1].0.set(0);
+    assert_eq!(0, tick_lines(&mut m));
+}
+
+#[test]
+fn compositemem_nmi_is_edge_triggered()
+{
+    let mut m =  CompositeMemory::new();
+    let src = interrupt_sources(&mut m, 2);
+    src[0].0.set(NMI_LINE);
+    src[1].0.set(IRQ_LINE);
+
+    assert_eq!(NMI_LINE | IRQ_LINE, tick_lines(&mut m));
+    assert_eq!(IRQ_LINE, tick_lines(&mut m));
+
+    src[0].0.set(0);
+    assert_eq!(IRQ_LINE, tick_lines(&mut m));
+    src[0].0.set(NMI_LINE);
+    assert_eq!(NMI_LINE | IRQ_LINE, tick_lines(&mut m));
+}
+
+// Not a correctness test: compares the page table against the linear
+// search it replaced. Run with
+// cargo test --release compositemem_lookup_benchmark -- --ignored --nocapture
+#[test]
+#[ignore]
+fn compositemem_lookup_benchmark()
+{
+    use std::time::Instant;
+
+    let mut m =  CompositeMemory::new();
+    m.register_range(0x2000, 0x2007, Box::new(RawMemory::new(0x08))).unwrap();
+    m.register_range(0x4014, 0x4014, Box::new(RawMemory::new(0x01))).unwrap();
+    m.register_range(0x4000, 0x4013, Box::new(RawMemory::new(0x14))).unwrap();
+    m.register_range(0x4015, 0x4017, Box::new(RawMemory::new(0x03))).unwrap();
+    m.register_range(0x6000, 0x7FFF, Box::new(RawMemory::new(0x2000))).unwrap();
+    m.register_range(0x0000, 0x1FFF, Box::new(RawMemory::new(0x2000))).unwrap();
+    m.register_range(0x8000, 0xFFFF, Box::new(RawMemory::new(0x8000))).unwrap();
+
+    const ROUNDS: usize = 200;
+    let mut hits = 0usize;
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS
+    {
+        for adr in 0..0x10000
+        {
+            hits += m.find_handler_linear(std::hint::black_box(adr)).unwrap_or(0);
+        }
+    }
+    let linear = start.elapsed();
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS
+    {
+        for adr in 0..0x10000
+        {
+            hits -= m.find_handler(std::hint::black_box(adr)).unwrap_or(0);
+        }
+    }
+    let paged = start.elapsed();
+
+    assert_eq!(0, hits);
+    println!("linear search: {:?}, page table: {:?} ({:.1}x)", linear, paged,
+             linear.as_secs_f64() / paged.as_secs_f64());
+}
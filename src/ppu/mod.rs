@@ -1,6 +1,8 @@
 use crate::memory::*;
 use crate::log;
-use std::{cell::RefCell, sync::{Arc,Mutex}};
+use crate::cartridge::Mirroring;
+use crate::mapper::{ciram_page, Mapper, Nametable, RenderPhase, SharedMapper};
+use std::{cell::RefCell, rc::Rc, sync::{Arc,Mutex}};
 
 
 const PIXELS_PER_SCANLINE: u16 = 256;
@@ -13,46 +15,271 @@ const Sprite0Occ: u8  = 0b01000000;
 const ScanSprCnt: u8  = 0b00100000;
 const VRAMWrite: u8   = 0b00010000; 
 
-const VRamAdrIncBit : u8 = 0b00000010;
+const VRAM_ADR_INC_32: u8 = 0b00000100;
+
+const SPRITE_TABLE_HIGH: u8 = 0b00001000;
+const BG_TABLE_HIGH: u8     = 0b00010000;
+const SPRITE_SIZE_16: u8    = 0b00100000;
+
+const SHOW_BACKGROUND: u8 = 0b00001000;
+const SHOW_SPRITES: u8    = 0b00010000;
+
+// $0000-$1FFF are the pattern tables, which live on the cartridge and are
+// accessed through its mapper.
+const PATTERN_TABLE_END: u16 = 0x2000;
+// $2000-$2FFF are the four nametables, $3000-$3EFF mirror them.
+const PALETTE_START: u16 = 0x3F00;
+const NAMETABLE_SIZE: usize = 0x0400;
+// The console has 2 KiB of CIRAM, four screen boards add another 2 KiB on
+// the cartridge. Both are kept here, pages 2 and 3 are only used when the
+// mapper asks for them.
+const CIRAM_SIZE: usize = 4 * NAMETABLE_SIZE;
+const PALETTE_SIZE: usize = 0x20;
 
 const pixels_per_tick : u16 = 3;
 
 pub struct ppu
 {
     ctrl0: u8,
+    ctrl1: u8,
     status: u8,
     line: u16,
     lastpixel: u16,
     vramadrbyte1: bool,
     vramadr: u16,
-    vram: [u8; 0x3FFF],
+    // $2007 reads below the palette return the byte fetched by the
+    // previous read.
+    read_buffer: u8,
+    ciram: [u8; CIRAM_SIZE],
+    palette: [u8; PALETTE_SIZE],
+    oam: [u8; 256],
+    oamadr: u8,
+    mapper: Option<SharedMapper>,
     logger: Arc<Mutex<log::logger>>,
-    framebuffer: Arc<RefCell<Vec<u32>>>   
+    framebuffer: Rc<RefCell<Vec<u32>>>   
 }
 
 impl ppu
 {
-    pub fn new(log: Arc<Mutex<log::logger>>, framebuffer: Arc<RefCell<Vec<u32>>>) -> Self
+    pub fn new(log: Arc<Mutex<log::logger>>, framebuffer: Rc<RefCell<Vec<u32>>>) -> Self
     {
         ppu {
             ctrl0: 0x00,
+            ctrl1: 0x00,
             status: 0,
             line: 0,
             lastpixel: 0,
             vramadrbyte1: false,
             vramadr: 0x000,
-            vram: [0; 0x3FFF],
+            read_buffer: 0,
+            ciram: [0; CIRAM_SIZE],
+            palette: [0; PALETTE_SIZE],
+            oam: [0; 256],
+            oamadr: 0,
+            mapper: None,
             logger: log,
             framebuffer: framebuffer
             }
     }
 
+    pub fn power_on(&mut self, state: PowerOnState)
+    {
+        state.fill(&mut self.ciram);
+        state.fill(&mut self.palette);
+    }
+
+    // Plugs in the cartridge, without one the pattern tables are empty.
+    pub fn set_mapper(&mut self, mapper: SharedMapper)
+    {
+        self.mapper = Some(mapper);
+    }
+
+    fn cartridge_peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        match &self.mapper
+        {
+            Some(m) => m.borrow().ppu_peek(address),
+            None => Err(MemError::BadAddress { address, access: AccessKind::Read, device: self.name() })
+        }
+    }
+
+    fn cartridge_write(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        match &self.mapper
+        {
+            Some(m) => m.borrow_mut().ppu_write(address, data),
+            None => Err(MemError::BadAddress { address, access: AccessKind::Write, device: self.name() })
+        }
+    }
+
+    // Where a nametable access goes. The mapper wires CIRAM, without a
+    // cartridge A10 is left as on a vertically mirrored board.
+    fn nametable(&self, address: usize) -> Nametable
+    {
+        match &self.mapper
+        {
+            Some(m) => m.borrow().nametable(address),
+            None => Nametable::Ciram(ciram_page(Mirroring::Vertical, address))
+        }
+    }
+
+    fn ciram_index(page: usize, address: usize) -> usize
+    {
+        (page * NAMETABLE_SIZE + (address & (NAMETABLE_SIZE - 1))) % CIRAM_SIZE
+    }
+
+    // VRAM as seen through $2007. Pattern tables and nametables the board
+    // provides itself go to the cartridge.
+    fn vram_peek(&self, address: u16) -> Result<u8, MemError>
+    {
+        let address = address & 0x3FFF;
+        if address < PATTERN_TABLE_END
+        {
+            return self.cartridge_peek(address as usize);
+        }
+        if address >= PALETTE_START
+        {
+            return Ok(self.palette[address as usize % PALETTE_SIZE]);
+        }
+
+        let address = 0x2000 | (address as usize & 0x0FFF);
+        match self.nametable(address)
+        {
+            Nametable::Ciram(page) => Ok(self.ciram[ppu::ciram_index(page, address)]),
+            Nametable::Cartridge => self.cartridge_peek(address)
+        }
+    }
+
+    fn vram_write(&mut self, address: u16, data: u8) -> Result<(), MemError>
+    {
+        let address = address & 0x3FFF;
+        if address < PATTERN_TABLE_END
+        {
+            return self.cartridge_write(address as usize, data);
+        }
+        if address >= PALETTE_START
+        {
+            self.palette[address as usize % PALETTE_SIZE] = data;
+            return Ok(());
+        }
+
+        let address = 0x2000 | (address as usize & 0x0FFF);
+        match self.nametable(address)
+        {
+            Nametable::Ciram(page) => self.ciram[ppu::ciram_index(page, address)] = data,
+            Nametable::Cartridge => self.cartridge_write(address, data)?
+        }
+        Ok(())
+    }
+
     pub fn log(&self, message: String)
     {
         let mut lg = self.logger.lock().unwrap();
         lg.write(message);
     }
 
+    // While rendering, the PPU fetches background tiles for dots 1-256 and
+    // sprite tiles for dots 257-320, from the pattern tables selected in
+    // ctrl0. Mappers watch these fetches: MMC2/MMC4 switch CHR banks when
+    // certain tiles are fetched, MMC3 counts rising edges of A12. A12 goes
+    // high once per line if either comes from $1000 (with both from $1000
+    // the nametable fetches in between toggle it, but MMC3 filters these
+    // short pulses). We do not draw dot by dot, so all fetches of a line
+    // happen here, in the order of the real PPU.
+    fn pattern_fetches(&self)
+    {
+        if self.ctrl1 & (SHOW_BACKGROUND | SHOW_SPRITES) == 0
+        {
+            return;
+        }
+        let mut mapper = match &self.mapper
+        {
+            Some(m) => m.borrow_mut(),
+            None => return
+        };
+        let line = self.line as usize;
+
+        // Scrolling is not implemented yet, the background always comes
+        // from the first nametable.
+        mapper.notify_render_phase(RenderPhase::Background);
+        let bg_table = if self.ctrl0 & BG_TABLE_HIGH != 0 { 0x1000 } else { 0x0000 };
+        for x in 0..32
+        {
+            let tile = self.fetch_nametable(&mut *mapper, 0x2000 + (line / 8) * 32 + x) as usize;
+            self.fetch_nametable(&mut *mapper, 0x23C0 + (line / 32) * 8 + x / 4);
+            ppu::fetch_pattern(&mut *mapper, bg_table + tile * 16 + line % 8);
+        }
+
+        // 8x16 sprites pick their table per tile, most games put them at $1000.
+        let tall = self.ctrl0 & SPRITE_SIZE_16 != 0;
+        if self.ctrl0 & (BG_TABLE_HIGH | SPRITE_TABLE_HIGH | SPRITE_SIZE_16) != 0
+        {
+            mapper.notify_a12_rise();
+        }
+
+        // Up to 8 sprites on the line, unused slots fetch tile $FF.
+        mapper.notify_render_phase(RenderPhase::Sprites);
+        let height = if tall { 16 } else { 8 };
+        let sprite_table = if self.ctrl0 & SPRITE_TABLE_HIGH != 0 { 0x1000 } else { 0x0000 };
+        let mut fetched = 0;
+        for sprite in self.oam.chunks(4)
+        {
+            let row = line.wrapping_sub(sprite[0] as usize);
+            if row >= height
+            {
+                continue;
+            }
+
+            let tile = sprite[1] as usize;
+            let address = if tall
+            {
+                (tile & 1) * 0x1000 + ((tile & 0xFE) + row / 8) * 16 + row % 8
+            }
+            else
+            {
+                sprite_table + tile * 16 + row
+            };
+            ppu::fetch_pattern(&mut *mapper, address);
+
+            fetched += 1;
+            if fetched == 8
+            {
+                break;
+            }
+        }
+        for _ in fetched..8
+        {
+            ppu::fetch_pattern(&mut *mapper, sprite_table + 0xFF * 16);
+        }
+    }
+
+    // A nametable or attribute byte as the renderer sees it.
+    fn fetch_nametable(&self, mapper: &mut dyn Mapper, address: usize) -> u8
+    {
+        let data = match mapper.nametable(address)
+        {
+            Nametable::Cartridge => mapper.ppu_read(address).unwrap_or(0),
+            Nametable::Ciram(page) => self.ciram[ppu::ciram_index(page, address)]
+        };
+        mapper.nametable_fetch(address, data)
+    }
+
+    // Both bit planes of a tile row. The data is not drawn yet.
+    fn fetch_pattern(mapper: &mut dyn Mapper, address: usize)
+    {
+        for plane in &[0, 8]
+        {
+            let _ = mapper.ppu_read(address + plane);
+            mapper.notify_pattern_fetch(address + plane);
+        }
+    }
+
+    fn increment_vramadr(&mut self)
+    {
+        let increment = if self.ctrl0 & VRAM_ADR_INC_32 == 0 { 1 } else { 32 };
+        self.vramadr = self.vramadr.wrapping_add(increment) & 0x3FFF;
+    }
+
     fn get_nametable_index(line: u32, pixel: u32) -> u32
     {
         let x = pixel / 32;
@@ -73,15 +300,32 @@ impl Memory for ppu
                //self.status &= !VBlankBit;
                return Ok(statuscopy)
             },
+            0x04 => return Ok(self.oam[self.oamadr as usize]),
+            0x07 => {
+                let data = self.vram_peek(self.vramadr)?;
+                // Palette reads skip the buffer, it gets the nametable byte
+                // underneath instead.
+                let result = if self.vramadr & 0x3FFF >= PALETTE_START
+                {
+                    self.read_buffer = self.vram_peek(self.vramadr - 0x1000)?;
+                    data
+                }
+                else
+                {
+                    std::mem::replace(&mut self.read_buffer, data)
+                };
+                self.increment_vramadr();
+                return Ok(result);
+            },
             _ => {}
         }
 
-        let err = format!("PPU.Read: {:#04x} -> Bad Addr", address);
+        let err = MemError::BadAddress { address, access: AccessKind::Read, device: self.name() };
         self.log(format!("{}", err));
-        Err(MemError::BadAddress)
+        Err(err)
     }
 
-    fn write_byte(&mut self, address: usize, data: u8) -> MemError
+    fn write_byte(&mut self, address: usize, data: u8) -> Result<(), MemError>
     {
         let actual_address = address + 0x2000;
 
@@ -89,27 +333,40 @@ impl Memory for ppu
         {
             0x2000 => {
                 self.log(format!("          PPU.Ctrl1 -> {:#2x}", data));
-                return MemError::Ok;
+                self.ctrl0 = data;
+                if let Some(m) = &self.mapper
+                {
+                    m.borrow_mut().notify_ppu_ctrl(self.ctrl0, self.ctrl1);
+                }
+                Ok(())
             },
             0x2001 => {
                 self.log(format!("          PPU.Ctrl2 -> {:#2x}", data));
-                return MemError::Ok;
+                self.ctrl1 = data;
+                if let Some(m) = &self.mapper
+                {
+                    m.borrow_mut().notify_ppu_ctrl(self.ctrl0, self.ctrl1);
+                }
+                Ok(())
             }
             0x2002 => {
-                panic!("Cannot write to PPU.Status");
+                Err(MemError::ReadOnly { address, access: AccessKind::Write, device: self.name() })
             },
             0x2003 => {
                 self.log(format!("          PPU.OAMADR -> {:#2x}", data));                
-                return MemError::Ok;
+                self.oamadr = data;
+                Ok(())
             },
             0x2004 =>
             {
-                panic!("no OAM support yet.");
+                self.oam[self.oamadr as usize] = data;
+                self.oamadr = self.oamadr.wrapping_add(1);
+                Ok(())
             },
              0x2005=> {
                  // Note: This comes as two values , one for x one for y
                 self.log(format!("          PPU.Scroll -> {:#2x}", data));
-                return MemError::Ok;
+                Ok(())
             },
             0x2006 => {
                 self.log(format!("          PPU.Addr -> {:#2x}", data));
@@ -120,34 +377,34 @@ impl Memory for ppu
                 }
                 else
                 {
-                    let datashift = datau16 << 8;
+                    // The PPU address space is 14 bits wide.
+                    let datashift = (datau16 & 0x3F) << 8;
                     self.vramadr = (self.vramadr & 0xFF) | datashift;
                 }
                 let logmsg = format!("          PPU.VRAMADR -> {:#2x}", self.vramadr);
                 self.log(logmsg);                
                 self.vramadrbyte1 = !self.vramadrbyte1;
-                return MemError::Ok;
+                Ok(())
             },
             0x2007 => {
                 self.log(format!("          PPU.Data -> {:#2x}", data));
                 self.log(format!("          PPU.VRAM {:#2x} -> {:#2x}", self.vramadr, data));
 
-                if self.vramadr > 0x3FFF
+                let vramadr = self.vramadr & 0x3FFF;
+                if vramadr < PATTERN_TABLE_END
                 {
-                    self.logger.lock().unwrap().to_console();
-                    panic!("Invalid VRAM access");
+                    if let Err(err) = self.cartridge_write(vramadr as usize, data)
+                    {
+                        self.log(format!("          PPU.CHR write ignored: {}", err));
+                    }
                 }
-
-                self.vram[self.vramadr as usize] = data;
-                let increment = if self.ctrl0 & VRamAdrIncBit == 0 { 1 } else { 32 };
-                self.vramadr += increment;
-                if self.vramadr >= 0x3FFF
+                else
                 {
-                    self.vramadr -= 0x3FFF;
+                    self.vram_write(vramadr, data)?;
                 }
 
-
-                return MemError::Ok;
+                self.increment_vramadr();
+                Ok(())
             },
 
             0x4014 => {
@@ -160,7 +417,7 @@ impl Memory for ppu
         // if address < self.data.len()
         // {
         //     self.data[address] = data;
-        //     return MemError::Ok;
+        //     return Ok(());
         // }
         // let err = format!("         PPU.Write: {:#04x} -> Bad Addr", address);
         // println!("{}", err);
@@ -169,6 +426,44 @@ impl Memory for ppu
 
 
 
+    fn peek(&self, address: usize) -> Result<u8, MemError>
+    {
+        // Unlike read_byte this neither touches the status flags nor the
+        // VRAM address.
+        match address
+        {
+            0x02 => Ok(self.status),
+            0x04 => Ok(self.oam[self.oamadr as usize]),
+            0x07 => self.vram_peek(self.vramadr),
+            _ => Err(MemError::BadAddress { address, access: AccessKind::Read, device: self.name() })
+        }
+    }
+
+    fn poke(&mut self, address: usize, data: u8) -> Result<(), MemError>
+    {
+        // Sets register contents directly, without toggling the address
+        // latch or incrementing the VRAM address.
+        match address
+        {
+            0x00 => self.ctrl0 = data,
+            0x02 => self.status = data,
+            0x03 => self.oamadr = data,
+            0x04 => self.oam[self.oamadr as usize] = data,
+            0x07 => self.vram_write(self.vramadr, data)?,
+            _ => return Err(MemError::BadAddress { address, access: AccessKind::Write, device: self.name() })
+        }
+        Ok(())
+    }
+
+    fn write_oam_dma(&mut self, data: &[u8; 256])
+    {
+        // Same as 256 writes to $2004, so OAMADR ends up where it started.
+        for (i, value) in data.iter().enumerate()
+        {
+            self.oam[self.oamadr.wrapping_add(i as u8) as usize] = *value;
+        }
+    }
+
     fn tick(&mut self, clock_ticks: u32) -> MemTickResult
     {
         let num_pixels_to_draw = clock_ticks as u32 * pixels_per_tick as u32;
@@ -183,7 +478,7 @@ impl Memory for ppu
             // step 1: calculate nametable index:
             let nametable_index = ppu::get_nametable_index(self.line as u32, i);
             // Fetch a nametable entry from $2000-$2FBF.
-            let nametable_entry = self.vram[0x2000 + nametable_index as usize];
+            let nametable_entry = self.vram_peek(0x2000 + nametable_index as u16).unwrap_or(0);
             // Fetch the corresponding attribute table entry from $23C0-$2FFF and increment the current VRAM address within the same row.
             // Fetch the low-order byte of an 8x1 pixel sliver of pattern table from $0000-$0FF7 or $1000-$1FF7.
             // Fetch the high-order byte of this sliver from an address 8 bytes higher.
@@ -198,8 +493,24 @@ impl Memory for ppu
         self.lastpixel += clock_ticks as u16 * pixels_per_tick;
         if self.lastpixel > PIXELS_PER_SCANLINE
         {
+            // The last line before wrapping around acts as pre-render line.
+            // Rendering stops where VBlank starts, so mappers counting
+            // lines do not see any after the NMI.
+            if self.line < VISIBLE_SCANLINES || self.line == VLBANKEND
+            {
+                self.pattern_fetches();
+            }
             self.line +=1;
             self.lastpixel = self.lastpixel % PIXELS_PER_SCANLINE;
+            if let Some(m) = &self.mapper
+            {
+                let mut m = m.borrow_mut();
+                m.notify_scanline();
+                if self.line == VISIBLE_SCANLINES
+                {
+                    m.notify_render_phase(RenderPhase::Idle);
+                }
+            }
 
             if self.line > VLBANKEND 
             {
@@ -214,13 +525,417 @@ impl Memory for ppu
                 if(status_cpy & VBlankBit) == 0
                 {
                     // Just entered VBlank, generate NMI.
-                    return MemTickResult::IRQ( 0b001 as u8)
+                    return MemTickResult::IRQ(NMI_LINE)
                 }
             }
 
         }
         MemTickResult::Ok        
     }
+
+    fn name(&self) -> &'static str
+    {
+        "PPU"
+    }
+}
+
+#[cfg(test)]
+mod peektests
+{
+    use crate::ppu::*;
+
+    fn setup() -> ppu
+    {
+        let fb = Rc::new(RefCell::new(vec![0u32; 320 * 240]));
+        ppu::new(Arc::new(Mutex::new(log::logger::new())), fb)
+    }
+
+    #[test]
+    fn peek_does_not_advance_vram_address()
+    {
+        let mut p = setup();
+        insert_cartridge(&mut p, vec![]);
+        p.write_byte(0x06, 0x21).unwrap();
+        p.write_byte(0x06, 0x08).unwrap();
+        p.write_byte(0x07, 0xAB).unwrap();
+        p.write_byte(0x06, 0x21).unwrap();
+        p.write_byte(0x06, 0x08).unwrap();
+
+        assert_eq!(0xAB, p.peek(0x07).unwrap());
+        assert_eq!(0xAB, p.peek(0x07).unwrap());
+        assert_eq!(0x0821, p.vramadr);
+    }
+
+    #[test]
+    fn ctrl_bit_2_increments_vram_address_by_32()
+    {
+        let mut p = setup();
+        insert_cartridge(&mut p, vec![]);
+        p.write_byte(0x00, 0x04).unwrap();
+        p.write_byte(0x06, 0x00).unwrap();
+        p.write_byte(0x06, 0x20).unwrap();
+        p.write_byte(0x07, 0xAB).unwrap();
+        p.write_byte(0x07, 0xCD).unwrap();
+
+        assert_eq!(0x2040, p.vramadr);
+    }
+
+    #[test]
+    fn poke_writes_vram_without_increment()
+    {
+        let mut p = setup();
+        p.vramadr = 0x2000;
+        p.poke(0x07, 0x55).unwrap();
+
+        assert_eq!(0x55, p.ciram[0x0000]);
+        assert_eq!(0x2000, p.vramadr);
+    }
+
+    #[test]
+    fn write_to_status_is_rejected()
+    {
+        let mut p = setup();
+        assert_eq!(Err(MemError::ReadOnly { address: 0x02, access: AccessKind::Write, device: "PPU" }),
+                   p.write_byte(0x02, 0x00));
+    }
+
+    // Plugs in an NROM cartridge, CHR RAM if chr is empty.
+    fn insert_cartridge(p: &mut ppu, chr: Vec<u8>)
+    {
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, 1, (chr.len() / 0x2000) as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        rom.extend(vec![0x00; 0x4000]);
+        rom.extend(chr);
+        let cart = crate::cartridge::Cartridge::from_bytes(&rom).unwrap();
+        p.set_mapper(crate::mapper::create(cart, PowerOnState::Zeros).unwrap());
+    }
+
+    fn set_vramadr(p: &mut ppu, address: u16)
+    {
+        p.write_byte(0x06, (address & 0xFF) as u8).unwrap();
+        p.write_byte(0x06, (address >> 8) as u8).unwrap();
+    }
+
+    #[test]
+    fn pattern_tables_show_chr_rom()
+    {
+        let mut p = setup();
+        let mut chr = vec![0x00; 0x2000];
+        chr[0x1234] = 0x3C;
+        insert_cartridge(&mut p, chr);
+
+        set_vramadr(&mut p, 0x1234);
+
+        assert_eq!(0x3C, p.peek(0x07).unwrap());
+    }
+
+    #[test]
+    fn writes_to_chr_rom_are_ignored()
+    {
+        let mut p = setup();
+        insert_cartridge(&mut p, vec![0x11; 0x2000]);
+
+        set_vramadr(&mut p, 0x0010);
+        p.write_byte(0x07, 0xFF).unwrap();
+        set_vramadr(&mut p, 0x0010);
+
+        assert_eq!(0x11, p.peek(0x07).unwrap());
+    }
+
+    #[test]
+    fn writes_to_chr_rom_aliases_are_ignored()
+    {
+        let mut p = setup();
+        insert_cartridge(&mut p, vec![0x11; 0x2000]);
+
+        p.vramadr = 0x4010;
+        p.write_byte(0x07, 0xFF).unwrap();
+        set_vramadr(&mut p, 0x0010);
+
+        assert_eq!(0x11, p.peek(0x07).unwrap());
+    }
+
+    #[test]
+    fn chr_ram_is_writable_through_data_port()
+    {
+        let mut p = setup();
+        insert_cartridge(&mut p, vec![]);
+
+        set_vramadr(&mut p, 0x1FFF);
+        p.write_byte(0x07, 0xA5).unwrap();
+
+        assert_eq!(0x2000, p.vramadr);
+        set_vramadr(&mut p, 0x1FFF);
+        assert_eq!(0xA5, p.peek(0x07).unwrap());
+    }
+
+    #[test]
+    fn vram_address_wraps_from_3fff_to_0000()
+    {
+        let mut p = setup();
+        insert_cartridge(&mut p, vec![]);
+
+        set_vramadr(&mut p, 0xFFFF);
+        assert_eq!(0x3FFF, p.vramadr);
+        p.write_byte(0x07, 0x0F).unwrap();
+        p.write_byte(0x07, 0xA5).unwrap();
+
+        assert_eq!(0x0001, p.vramadr);
+        set_vramadr(&mut p, 0x0000);
+        assert_eq!(0xA5, p.peek(0x07).unwrap());
+
+        p.write_byte(0x00, 0x04).unwrap();
+        set_vramadr(&mut p, 0x3FF0);
+        p.write_byte(0x07, 0x0F).unwrap();
+        assert_eq!(0x0010, p.vramadr);
+    }
+
+    struct A12Probe
+    {
+        rises: std::rc::Rc<std::cell::Cell<u32>>
+    }
+
+    impl crate::mapper::Mapper for A12Probe
+    {
+        fn cpu_write(&mut self, _address: usize, _data: u8) -> Result<(), MemError> { Ok(()) }
+        fn cpu_peek(&self, _address: usize) -> Result<u8, MemError> { Ok(0) }
+        fn ppu_write(&mut self, _address: usize, _data: u8) -> Result<(), MemError> { Ok(()) }
+        fn ppu_peek(&self, _address: usize) -> Result<u8, MemError> { Ok(0) }
+        fn mirroring(&self) -> crate::cartridge::Mirroring { crate::cartridge::Mirroring::Vertical }
+        fn notify_a12_rise(&mut self) { self.rises.set(self.rises.get() + 1); }
+        fn name(&self) -> &'static str { "A12Probe" }
+    }
+
+    // Runs one frame and returns the number of A12 rises the mapper saw.
+    fn a12_rises_per_frame(ctrl0: u8, ctrl1: u8) -> u32
+    {
+        let mut p = setup();
+        let rises = std::rc::Rc::new(std::cell::Cell::new(0));
+        p.set_mapper(std::rc::Rc::new(RefCell::new(A12Probe { rises: rises.clone() })));
+        p.write_byte(0x00, ctrl0).unwrap();
+        p.write_byte(0x01, ctrl1).unwrap();
+
+        for _ in 0..=VLBANKEND
+        {
+            p.tick(86);
+        }
+        rises.get()
+    }
+
+    struct FetchProbe
+    {
+        fetches: std::rc::Rc<RefCell<Vec<usize>>>
+    }
+
+    impl crate::mapper::Mapper for FetchProbe
+    {
+        fn cpu_write(&mut self, _address: usize, _data: u8) -> Result<(), MemError> { Ok(()) }
+        fn cpu_peek(&self, _address: usize) -> Result<u8, MemError> { Ok(0) }
+        fn ppu_write(&mut self, _address: usize, _data: u8) -> Result<(), MemError> { Ok(()) }
+        fn ppu_peek(&self, _address: usize) -> Result<u8, MemError> { Ok(0) }
+        fn mirroring(&self) -> crate::cartridge::Mirroring { crate::cartridge::Mirroring::Vertical }
+        fn notify_pattern_fetch(&mut self, address: usize) { self.fetches.borrow_mut().push(address); }
+        fn name(&self) -> &'static str { "FetchProbe" }
+    }
+
+    #[test]
+    fn rendered_line_fetches_background_and_sprite_patterns()
+    {
+        let mut p = setup();
+        let fetches = std::rc::Rc::new(RefCell::new(vec![]));
+        p.set_mapper(std::rc::Rc::new(RefCell::new(FetchProbe { fetches: fetches.clone() })));
+        p.ciram[0x0005] = 0xFD;
+        p.oam = [0xFF; 256];
+        p.oam[0] = 0;
+        p.oam[1] = 0x42;
+        p.write_byte(0x00, SPRITE_TABLE_HIGH).unwrap();
+        p.write_byte(0x01, SHOW_BACKGROUND | SHOW_SPRITES).unwrap();
+
+        p.tick(86);
+
+        let fetches = fetches.borrow();
+        // 32 background tiles and 8 sprite slots, two bit planes each.
+        assert_eq!(80, fetches.len());
+        assert_eq!(&[0x0FD0, 0x0FD8], &fetches[10..12]);
+        assert_eq!(&[0x1420, 0x1428], &fetches[64..66]);
+        assert_eq!(&[0x1FF0, 0x1FF8], &fetches[78..80]);
+    }
+
+    #[test]
+    fn sprites_at_1000_raise_a12_once_per_rendered_line()
+    {
+        assert_eq!(VISIBLE_SCANLINES as u32 + 1, a12_rises_per_frame(SPRITE_TABLE_HIGH, SHOW_BACKGROUND | SHOW_SPRITES));
+    }
+
+    #[test]
+    fn no_a12_rise_between_nmi_and_pre_render_line()
+    {
+        let mut p = setup();
+        let rises = std::rc::Rc::new(std::cell::Cell::new(0));
+        p.set_mapper(std::rc::Rc::new(RefCell::new(A12Probe { rises: rises.clone() })));
+        p.write_byte(0x00, SPRITE_TABLE_HIGH).unwrap();
+        p.write_byte(0x01, SHOW_BACKGROUND | SHOW_SPRITES).unwrap();
+
+        while let MemTickResult::Ok = p.tick(86) {}
+        let at_nmi = rises.get();
+        while p.line < VLBANKEND
+        {
+            p.tick(86);
+        }
+
+        assert_eq!(VISIBLE_SCANLINES as u32, at_nmi);
+        assert_eq!(at_nmi, rises.get());
+    }
+
+    #[test]
+    fn no_a12_rise_when_rendering_is_off()
+    {
+        assert_eq!(0, a12_rises_per_frame(SPRITE_TABLE_HIGH, 0x00));
+    }
+
+    #[test]
+    fn no_a12_rise_when_both_tables_at_0000()
+    {
+        assert_eq!(0, a12_rises_per_frame(0x00, SHOW_BACKGROUND));
+    }
+
+    // Plugs in a board with 32 KiB PRG ROM and CHR RAM, flags6 holds the
+    // mirroring bits and the low nibble of the mapper number.
+    fn insert_board(p: &mut ppu, flags6: u8) -> crate::mapper::SharedMapper
+    {
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, 2, 0, flags6, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        rom.extend(vec![0x00; 0x8000]);
+        let cart = crate::cartridge::Cartridge::from_bytes(&rom).unwrap();
+        let mapper = crate::mapper::create(cart, PowerOnState::Zeros).unwrap();
+        p.set_mapper(mapper.clone());
+        mapper
+    }
+
+    // Reads address through $2007 like a game does, with a dummy read to
+    // fill the read buffer first.
+    fn read_vram(p: &mut ppu, address: u16) -> u8
+    {
+        set_vramadr(p, address);
+        p.read_byte(0x07).unwrap();
+        p.read_byte(0x07).unwrap()
+    }
+
+    // Writes a different value to the start of each nametable through
+    // $2007 and reads all four back.
+    fn nametable_contents(p: &mut ppu) -> [u8; 4]
+    {
+        for (i, address) in [0x2000, 0x2400, 0x2800, 0x2C00].iter().enumerate()
+        {
+            set_vramadr(p, *address);
+            p.write_byte(0x07, 0x10 + i as u8).unwrap();
+        }
+        let mut result = [0; 4];
+        for (i, address) in [0x2000, 0x2400, 0x2800, 0x2C00].iter().enumerate()
+        {
+            result[i] = read_vram(p, *address);
+        }
+        result
+    }
+
+    #[test]
+    fn horizontal_mirroring_pairs_top_and_bottom()
+    {
+        let mut p = setup();
+        insert_board(&mut p, 0x00);
+
+        assert_eq!([0x11, 0x11, 0x13, 0x13], nametable_contents(&mut p));
+    }
+
+    #[test]
+    fn vertical_mirroring_pairs_left_and_right()
+    {
+        let mut p = setup();
+        insert_board(&mut p, 0x01);
+
+        assert_eq!([0x12, 0x13, 0x12, 0x13], nametable_contents(&mut p));
+    }
+
+    #[test]
+    fn four_screen_nametables_are_independent()
+    {
+        let mut p = setup();
+        insert_board(&mut p, 0x08);
+
+        assert_eq!([0x10, 0x11, 0x12, 0x13], nametable_contents(&mut p));
+    }
+
+    #[test]
+    fn mapper_selects_single_screen()
+    {
+        let mut p = setup();
+        // AxROM, bit 4 of the bank register picks the CIRAM page.
+        let mapper = insert_board(&mut p, 0x70);
+
+        mapper.borrow_mut().cpu_write(0x8000, 0x00).unwrap();
+        assert_eq!([0x13; 4], nametable_contents(&mut p));
+
+        mapper.borrow_mut().cpu_write(0x8000, 0x10).unwrap();
+        assert_eq!(0x00, read_vram(&mut p, 0x2000));
+        mapper.borrow_mut().cpu_write(0x8000, 0x00).unwrap();
+        assert_eq!(0x13, read_vram(&mut p, 0x2000));
+    }
+
+    #[test]
+    fn nametables_repeat_at_3000()
+    {
+        let mut p = setup();
+        insert_board(&mut p, 0x01);
+
+        set_vramadr(&mut p, 0x2C3F);
+        p.write_byte(0x07, 0x77).unwrap();
+        assert_eq!(0x77, read_vram(&mut p, 0x3C3F));
+
+        set_vramadr(&mut p, 0x3405);
+        p.write_byte(0x07, 0x66).unwrap();
+        assert_eq!(0x66, read_vram(&mut p, 0x2405));
+    }
+
+    #[test]
+    fn data_reads_go_through_read_buffer()
+    {
+        let mut p = setup();
+        insert_board(&mut p, 0x01);
+        set_vramadr(&mut p, 0x2000);
+        p.write_byte(0x07, 0x11).unwrap();
+        p.write_byte(0x07, 0x22).unwrap();
+
+        set_vramadr(&mut p, 0x2000);
+        assert_eq!(0x00, p.read_byte(0x07).unwrap());
+        assert_eq!(0x11, p.read_byte(0x07).unwrap());
+        assert_eq!(0x22, p.read_byte(0x07).unwrap());
+        assert_eq!(0x2003, p.vramadr);
+    }
+
+    #[test]
+    fn palette_reads_skip_read_buffer()
+    {
+        let mut p = setup();
+        insert_board(&mut p, 0x01);
+        set_vramadr(&mut p, 0x2F01);
+        p.write_byte(0x07, 0x33).unwrap();
+        set_vramadr(&mut p, 0x3F01);
+        p.write_byte(0x07, 0x0C).unwrap();
+
+        set_vramadr(&mut p, 0x3F01);
+        assert_eq!(0x0C, p.read_byte(0x07).unwrap());
+        // The buffer now holds the nametable byte below the palette.
+        set_vramadr(&mut p, 0x2000);
+        assert_eq!(0x33, p.read_byte(0x07).unwrap());
+    }
+
+    #[test]
+    fn peek_status_keeps_vblank()
+    {
+        let mut p = setup();
+        p.poke(0x02, VBlankBit).unwrap();
+
+        assert_eq!(VBlankBit, p.peek(0x02).unwrap());
+        assert_eq!(VBlankBit, p.peek(0x02).unwrap());
+    }
 }
 
 // #[cfg(test)]